@@ -0,0 +1,173 @@
+use crate::converter::ConvertOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// A converted notebook together with the hash of the input it was produced from
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    hash: u64,
+    pub markdown: String,
+    /// File names of the assets the markdown links to
+    pub assets: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    options_hash: u64,
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// On-disk cache of converted notebooks so unchanged notebooks are not reconverted on every build
+pub struct ConversionCache {
+    path: PathBuf,
+    file: CacheFile,
+    dirty: bool,
+}
+
+impl ConversionCache {
+    /// Loads the cache at `path`. A missing or unreadable cache, or one written with
+    /// different options, starts out empty.
+    pub fn load(path: &Path, options_hash: u64) -> Self {
+        let file = fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<CacheFile>(&bytes).ok())
+            .filter(|file| file.options_hash == options_hash)
+            .unwrap_or_else(|| CacheFile {
+                options_hash,
+                entries: HashMap::new(),
+            });
+
+        ConversionCache {
+            path: path.to_path_buf(),
+            file,
+            dirty: false,
+        }
+    }
+
+    /// Returns the cached conversion of `key` if it was produced from input with the same hash
+    pub fn get(&self, key: &str, hash: u64) -> Option<&CacheEntry> {
+        self.file.entries.get(key).filter(|entry| entry.hash == hash)
+    }
+
+    pub fn insert(&mut self, key: String, hash: u64, markdown: String, assets: Vec<String>) {
+        self.file.entries.insert(key, CacheEntry { hash, markdown, assets });
+        self.dirty = true;
+    }
+
+    /// Writes the cache back to disk if anything changed
+    pub fn save(&self) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_vec(&self.file)?)?;
+        Ok(())
+    }
+}
+
+/// Hashes the conversion options, including the target renderer
+pub fn options_hash(options: &ConvertOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    // going through `Value` sorts map keys, so HashMap options hash the same on every run
+    serde_json::to_value(options)
+        .map(|value| value.to_string())
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:?}", options.renderer).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes a notebook's bytes together with the options hash
+pub fn content_hash(bytes: &[u8], options_hash: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    options_hash.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_entries_load_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        let mut cache = ConversionCache::load(&path, 1);
+        assert!(cache.get("a.ipynb", 10).is_none());
+        cache.insert("a.ipynb".to_string(), 10, "# A".to_string(), vec!["img_0123abcd.png".to_string()]);
+        cache.save().unwrap();
+
+        let cache = ConversionCache::load(&path, 1);
+        let entry = cache.get("a.ipynb", 10).unwrap();
+        assert_eq!(entry.markdown, "# A");
+        assert_eq!(entry.assets, ["img_0123abcd.png"]);
+    }
+
+    #[test]
+    fn changed_content_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ConversionCache::load(&dir.path().join("cache.json"), 1);
+        cache.insert("a.ipynb".to_string(), 10, "# A".to_string(), vec!["img_0123abcd.png".to_string()]);
+
+        assert!(cache.get("a.ipynb", 11).is_none());
+        assert!(cache.get("b.ipynb", 10).is_none());
+    }
+
+    #[test]
+    fn changed_options_invalidate_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        let mut cache = ConversionCache::load(&path, 1);
+        cache.insert("a.ipynb".to_string(), 10, "# A".to_string(), vec!["img_0123abcd.png".to_string()]);
+        cache.save().unwrap();
+
+        assert!(ConversionCache::load(&path, 2).get("a.ipynb", 10).is_none());
+    }
+
+    #[test]
+    fn unchanged_cache_is_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        ConversionCache::load(&path, 1).save().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unreadable_cache_starts_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        fs::write(&path, "not json").unwrap();
+
+        assert!(ConversionCache::load(&path, 1).get("a.ipynb", 10).is_none());
+    }
+
+    #[test]
+    fn cache_without_asset_lists_starts_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        fs::write(&path, r##"{"options_hash": 1, "entries": {"a.ipynb": {"hash": 10, "markdown": "# A"}}}"##).unwrap();
+
+        assert!(ConversionCache::load(&path, 1).get("a.ipynb", 10).is_none());
+    }
+
+    #[test]
+    fn options_hash_depends_on_renderer() {
+        let html = ConvertOptions::default();
+        let markdown = ConvertOptions {
+            renderer: crate::converter::Renderer::Markdown,
+            ..ConvertOptions::default()
+        };
+        assert_eq!(options_hash(&html), options_hash(&ConvertOptions::default()));
+        assert_ne!(options_hash(&html), options_hash(&markdown));
+    }
+}
@@ -1,10 +1,31 @@
+use crate::converter::{convert_notebook_to_md_with_options, convert_notebook_to_writer, notebook_title, ConvertOptions};
+use crate::JupyterPreprocessor;
 use anyhow::anyhow;
+use log::{error, info, warn};
 use mdbook::preprocess::Preprocessor;
+use mdbook::MDBook;
 use semver::{Version, VersionReq};
-use std::fs;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use toml_edit::{value, Array, DocumentMut, Item, Table};
 
-/// Handle the install command to add preprocessor config to book.toml
-pub fn handle_install() -> anyhow::Result<()> {
+/// Options written under `[preprocessor.jupyter]` by the install command
+#[derive(Debug, Default)]
+pub struct InstallOptions {
+    pub embed_images: bool,
+    /// Register the preprocessor for the renderers it supports (`renderers = ["html", "markdown"]`)
+    pub renderers: bool,
+    /// Preprocessors this one must run before
+    pub before: Vec<String>,
+    /// Preprocessors this one must run after
+    pub after: Vec<String>,
+}
+
+/// Handle the install command to add preprocessor config to book.toml.
+/// Existing keys are updated in place and the rest of the file keeps its formatting.
+pub fn handle_install(install: &InstallOptions) -> anyhow::Result<()> {
     let mut config_path = std::env::current_dir()?;
     config_path.push("book.toml");
 
@@ -15,18 +36,240 @@ pub fn handle_install() -> anyhow::Result<()> {
         ));
     }
 
-    let mut config_str = fs::read_to_string(&config_path)?;
-    if !config_str.contains("[preprocessor.jupyter]") {
-        config_str.push_str("\n[preprocessor.jupyter]\n");
-        fs::write(&config_path, config_str)?;
-        println!("Added [preprocessor.jupyter] to book.toml");
+    let config_str = fs::read_to_string(&config_path)?;
+    let mut doc = config_str.parse::<DocumentMut>()?;
+
+    // `[preprocessor]` itself stays implicit so only `[preprocessor.jupyter]` shows up in the file
+    let mut implicit = Table::new();
+    implicit.set_implicit(true);
+    let preprocessors = doc
+        .entry("preprocessor")
+        .or_insert(Item::Table(implicit))
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("`preprocessor` in book.toml is not a table"))?;
+
+    let existed = preprocessors.contains_key("jupyter");
+    let jupyter = preprocessors
+        .entry("jupyter")
+        .or_insert(Item::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("`preprocessor.jupyter` in book.toml is not a table"))?;
+
+    if install.embed_images {
+        jupyter["embed_images"] = value(true);
+    }
+    if install.renderers {
+        merge_string_array(jupyter, "renderers", &["html".to_string(), "markdown".to_string()]);
+    }
+    merge_string_array(jupyter, "before", &install.before);
+    merge_string_array(jupyter, "after", &install.after);
+
+    let updated = doc.to_string();
+    if updated != config_str {
+        fs::write(&config_path, updated)?;
+    }
+
+    if existed {
+        println!("Updated [preprocessor.jupyter] in book.toml");
     } else {
-        println!("[preprocessor.jupyter] already exists in book.toml");
+        println!("Added [preprocessor.jupyter] to book.toml");
     }
 
     Ok(())
 }
 
+/// Adds `names` to the string array at `key`, creating it if needed and skipping names already present
+fn merge_string_array(table: &mut Table, key: &str, names: &[String]) {
+    if names.is_empty() {
+        return;
+    }
+
+    let item = table.entry(key).or_insert(value(Array::new()));
+    if item.as_array().is_none() {
+        *item = value(Array::new());
+    }
+    if let Some(array) = item.as_array_mut() {
+        for name in names {
+            if !array.iter().any(|v| v.as_str() == Some(name.as_str())) {
+                array.push(name.as_str());
+            }
+        }
+    }
+}
+
+/// Handle the convert command: convert one notebook and write the markdown to a file or stdout.
+/// Image assets go to an `assets` directory next to the output file (or the current directory).
+pub fn handle_convert(input: &Path, output: Option<&Path>, embed_images: bool, dry_run: bool) -> anyhow::Result<()> {
+    let assets_dir = match output.and_then(|o| o.parent()) {
+        Some(parent) => parent.join("assets"),
+        None => std::env::current_dir()?.join("assets"),
+    };
+
+    let options = ConvertOptions {
+        embed_images,
+        dry_run,
+        ..ConvertOptions::default()
+    };
+    let book_path = input.file_name().map(Path::new).unwrap_or(input);
+
+    if dry_run {
+        // Report what would have been written instead of writing it
+        let result = convert_notebook_to_md_with_options(input, book_path, &assets_dir, options)?;
+        for asset in result.assets.iter().filter(|asset| asset.written) {
+            info!("Would write {} ({} bytes)", asset.path.display(), asset.size);
+        }
+        if let Some(path) = output {
+            info!("Would write {}", path.display());
+        }
+        return Ok(());
+    }
+
+    // The markdown is streamed out cell by cell so large notebooks aren't held in memory
+    match output {
+        Some(path) => {
+            write_markdown(input, book_path, &assets_dir, options, path)?;
+            info!("Wrote {}", path.display());
+        }
+        None => {
+            convert_notebook_to_writer(input, book_path, &assets_dir, options, io::stdout().lock())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the markdown of a notebook into `out_path`, removing the partial file if conversion fails
+fn write_markdown(
+    input: &Path,
+    book_path: &Path,
+    assets_dir: &Path,
+    options: ConvertOptions,
+    out_path: &Path,
+) -> anyhow::Result<()> {
+    let file = BufWriter::new(File::create(out_path)?);
+    if let Err(e) = convert_notebook_to_writer(input, book_path, assets_dir, options, file) {
+        let _ = fs::remove_file(out_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Handle the convert-dir command: convert every notebook under `src` into a markdown file at
+/// the same relative path under `dest`. Image assets go to `dest/assets`.
+pub fn handle_convert_dir(src: &Path, dest: &Path, embed_images: bool) -> anyhow::Result<()> {
+    let mut notebooks = Vec::new();
+    collect_notebooks(src, &mut notebooks)?;
+    notebooks.sort();
+
+    let assets_dir = dest.join("assets");
+    let options = ConvertOptions {
+        embed_images,
+        ..ConvertOptions::default()
+    };
+
+    let mut failed = 0;
+    for path in &notebooks {
+        // Relative paths keep asset names unique and make image links climb back to `dest`
+        let book_path = path.strip_prefix(src)?;
+        let out_path = dest.join(book_path).with_extension("md");
+
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = write_markdown(path, book_path, &assets_dir, options.clone(), &out_path) {
+            error!("Error converting notebook '{}': {}", path.display(), e);
+            failed += 1;
+        }
+    }
+
+    info!(
+        "Converted {} of {} notebooks into {}",
+        notebooks.len() - failed,
+        notebooks.len(),
+        dest.display()
+    );
+    if failed > 0 {
+        return Err(anyhow!("{} notebooks failed to convert", failed));
+    }
+
+    Ok(())
+}
+
+/// Handle the gen-summary command: print a SUMMARY.md fragment linking every notebook under
+/// `src`, titled by its first heading. Notebooks in subdirectories are nested under a draft
+/// chapter for each directory.
+pub fn handle_gen_summary(src: &Path) -> anyhow::Result<()> {
+    let mut notebooks = Vec::new();
+    collect_notebooks(src, &mut notebooks)?;
+    notebooks.sort();
+
+    let mut listed_dirs = HashSet::new();
+    for path in &notebooks {
+        let relative = path.strip_prefix(src)?;
+        // Every nested entry needs a parent one level up, or the indentation isn't a list
+        let mut dir = PathBuf::new();
+        for (depth, component) in relative.parent().into_iter().flat_map(Path::components).enumerate() {
+            dir.push(component);
+            if listed_dirs.insert(dir.clone()) {
+                let name = component.as_os_str().to_string_lossy();
+                println!("{}- [{}]()", "  ".repeat(depth), summary_text(&name));
+            }
+        }
+
+        let title = match notebook_title(path) {
+            Ok(Some(title)) => title,
+            Ok(None) => file_stem(relative),
+            Err(e) => {
+                warn!("Could not read the title of '{}': {}", path.display(), e);
+                file_stem(relative)
+            }
+        };
+
+        let depth = relative.components().count() - 1;
+        let link: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+        println!("{}- [{}]({})", "  ".repeat(depth), summary_text(&title), link.join("/"));
+    }
+
+    Ok(())
+}
+
+/// Escapes `text` for the link text of a SUMMARY.md entry, where a bracket would end it early
+fn summary_text(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]")
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Recursively collects `.ipynb` files, skipping hidden directories such as `.ipynb_checkpoints`
+fn collect_notebooks(dir: &Path, notebooks: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+            if !hidden {
+                collect_notebooks(&path, notebooks)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "ipynb") {
+            notebooks.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle the check command: convert every notebook of the book in `dir` without writing
+/// anything, failing if any notebook doesn't convert
+pub fn handle_check(dir: &Path) -> anyhow::Result<()> {
+    let book = MDBook::load(dir)?;
+    let preprocessor = JupyterPreprocessor::from_config(&book.config);
+    preprocessor.check(&book.root, &book.config, &book.book)?;
+    Ok(())
+}
+
 /// Check version compatibility with mdbook
 pub fn check_version_compatibility(mdbook_version: &str) -> Result<(), String> {
     let version_req = VersionReq::parse(&format!("^{}", mdbook::MDBOOK_VERSION))
@@ -35,8 +278,8 @@ pub fn check_version_compatibility(mdbook_version: &str) -> Result<(), String> {
         .expect("mdbook_version is a valid version string");
 
     if !version_req.matches(&version) {
-        eprintln!(
-            "Warning: The jupyter preprocessor was built against mdbook version {}, \
+        warn!(
+            "The jupyter preprocessor was built against mdbook version {}, \
              but we're being called from version {}",
             mdbook::MDBOOK_VERSION,
             mdbook_version
@@ -1,9 +1,21 @@
-use anyhow::Result;
+use crate::error::{ConvertError, Result};
+#[cfg(feature = "images")]
+use base64::alphabet;
+#[cfg(feature = "images")]
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+#[cfg(feature = "images")]
 use base64::{Engine as _, engine::general_purpose::STANDARD};
-use serde::{Deserialize, Serialize};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{Map, Value};
-use std::fs::{File, create_dir_all};
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::io::{Read, Write};
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
 
 /// Configuration options for notebook conversion
@@ -12,12 +24,332 @@ pub struct ConvertOptions {
     /// If true, embed images as base64 in the markdown instead of saving to files
     #[serde(default)]
     pub embed_images: bool,
+    /// Alt text for output images whose cell sets none; `{cell}` is replaced by the cell index
+    #[serde(default = "default_alt")]
+    pub default_alt: String,
+    /// Fence language used for code cells when the notebook metadata does not name one
+    #[serde(default = "default_language")]
+    pub default_language: String,
+    /// Replaces code cell languages highlight.js doesn't know; an empty string gives a bare fence
+    #[serde(default)]
+    pub unknown_language_fallback: Option<String>,
+    /// If true, remove ANSI escape sequences from error tracebacks, streams and `text/plain` outputs
+    #[serde(default = "default_true")]
+    pub strip_ansi: bool,
+    /// If true, streams, `text/plain` outputs and tracebacks containing ANSI colors are shown in a
+    /// `<pre>` with colored and bold spans instead of a fence (HTML renderer only)
+    #[serde(default)]
+    pub ansi_to_html: bool,
+    /// CSS class (or fence info string for non-HTML renderers) applied to stderr streams
+    #[serde(default = "default_stderr_class")]
+    pub stderr_class: String,
+    /// CSS class of the container wrapping error outputs (HTML renderer only)
+    #[serde(default = "default_error_class")]
+    pub error_class: String,
+    /// If true, code cell fences are lengthened when the source itself contains backtick fences
+    #[serde(default = "default_true")]
+    pub fence_guard: bool,
+    /// If true, wrap code cell source in `<div class="jupyter-input">` (HTML renderer only)
+    #[serde(default = "default_true")]
+    pub wrap_input: bool,
+    /// If true, mark each output's wrapper with `data-cell="N" data-output="M"` (HTML renderer only)
+    #[serde(default)]
+    pub data_attributes: bool,
+    /// If true, wrap a code cell's input and outputs together in `<div class="jupyter-cell">` (HTML renderer only)
+    #[serde(default)]
+    pub group_cell: bool,
+    /// If true, wrap each output in `<div class="jupyter-output">` (HTML renderer only)
+    #[serde(default = "default_true")]
+    pub wrap_output: bool,
+    /// If true, write `text/html` outputs verbatim instead of in an `html` fence (HTML renderer only)
+    #[serde(default = "default_true")]
+    pub render_html_output: bool,
+    /// If true, `text/markdown` outputs are rendered; otherwise they are shown in a `markdown` fence
+    #[serde(default = "default_true")]
+    pub render_markdown_output: bool,
+    /// Truncate stream, `text/plain` and traceback outputs longer than this many lines
+    #[serde(default)]
+    pub max_output_lines: Option<usize>,
+    /// Downscale PNG, JPEG and WebP outputs wider than this many pixels (needs the `resize` feature)
+    #[serde(default)]
+    pub max_image_width: Option<u32>,
+    /// Re-encode PNG and JPEG outputs to this format; only `"webp"` is supported (needs the
+    /// `webp` feature)
+    #[serde(default)]
+    pub convert_images_to: Option<String>,
+    /// If true, log a warning when code cells' execution counts are not ascending
+    #[serde(default)]
+    pub warn_out_of_order: bool,
+    /// If true, label code cells `[1]`, `[2]`, ... in the order they appear
+    #[serde(default)]
+    pub number_cells: bool,
+    /// If true, prefix code cells and execute results with `In [n]:` / `Out [n]:` prompts
+    #[serde(default)]
+    pub show_prompts: bool,
+    /// If true, convert the notebooks of a book in parallel
+    #[serde(default = "default_true")]
+    pub parallel: bool,
+    /// If true, remove previously generated asset files before regenerating them
+    #[serde(default = "default_true")]
+    pub clean_assets: bool,
+    /// Collapse outputs longer than this many lines into a `<details>` block (HTML renderer only)
+    #[serde(default)]
+    pub collapse_output_over_lines: Option<usize>,
+    /// If true, render Plotly and Vega outputs as interactive charts loaded from a CDN (HTML renderer only)
+    #[serde(default)]
+    pub render_interactive: bool,
+    /// Maps kernel language names to the highlight.js token used in code fences (e.g. `ipython3` -> `python`).
+    /// Entries from book.toml are added to the built-in defaults.
+    #[serde(default = "default_language_aliases")]
+    pub language_aliases: HashMap<String, String>,
+    /// If true, markdown cells tagged `note`, `warning`, `tip`, ... become mdbook-admonish blocks
+    #[serde(default)]
+    pub admonition_from_tags: bool,
+    /// Info string for stream and `text/plain` output fences (e.g. `"text"`); bare fences when unset
+    #[serde(default)]
+    pub output_fence_lang: Option<String>,
+    /// If true, mark stream and `text/plain` output fences `no-copy` so themes can drop their copy button
+    #[serde(default)]
+    pub output_no_copy: bool,
+    /// If true, keep blank code cells without outputs and empty stream/text outputs
+    #[serde(default)]
+    pub keep_empty_cells: bool,
+    /// If true, drop a leading `# Heading` from the first cell when it is markdown, since mdbook
+    /// titles chapters from SUMMARY.md
+    #[serde(default)]
+    pub strip_first_heading: bool,
+    /// If true, start the chapter with `# <metadata.title>` unless the first cell is already a heading
+    #[serde(default)]
+    pub title_from_metadata: bool,
+    /// If true, images whose output metadata gives a width/height become sized `<img>` tags (HTML renderer only)
+    #[serde(default)]
+    pub use_img_dimensions: bool,
+    /// If true, write SVG outputs inline into the page instead of as images (HTML renderer only)
+    #[serde(default)]
+    pub inline_svg: bool,
+    /// If true, insert `<!-- cell N (type) -->` comments to map output back to notebook cells
+    #[serde(default)]
+    pub cell_markers: bool,
+    /// If true, cells collapsed in JupyterLab (`metadata.jupyter`) are collapsed like `hide-*` tags
+    #[serde(default = "default_true")]
+    pub respect_jupyter_collapse: bool,
+    /// If true, remove leading `%`/`%%` magic and `!` shell lines from code cell source
+    #[serde(default)]
+    pub strip_magics: bool,
+    /// If true, comment out leading magic and shell lines instead; `strip_magics` takes precedence
+    #[serde(default)]
+    pub comment_magics: bool,
+    /// Regular expressions; code cell source lines matching any of them are removed
+    #[serde(default)]
+    pub strip_patterns: Vec<String>,
+    /// If true, emit `<a id="cell-N"></a>` before each cell for deep linking (HTML renderer only)
+    #[serde(default)]
+    pub cell_anchors: bool,
+    /// If true, emit `<!-- slide: TYPE -->` comments from `metadata.slideshow.slide_type`
+    #[serde(default)]
+    pub emit_slide_markers: bool,
+    /// If true, `application/javascript` outputs are emitted as `<script>` tags (HTML renderer only)
+    #[serde(default)]
+    pub allow_scripts: bool,
+    /// Assets directory relative to the build dir; the first component is the renderer's output
+    /// directory (default `html/assets`)
+    #[serde(default)]
+    pub assets_dir: Option<String>,
+    /// If true, notebooks whose nbformat version isn't 3 or 4 fail to convert instead of warning
+    #[serde(default)]
+    pub strict_nbformat: bool,
+    /// If true, of the `display_data` outputs sharing a `transient.display_id` only the last is shown
+    #[serde(default = "default_true")]
+    pub collapse_display_updates: bool,
+    /// If true, adjacent markdown cells are separated by a line break instead of a blank line, so
+    /// lists and tables can continue across cells (paragraphs in separate cells then merge too)
+    #[serde(default)]
+    pub join_markdown_cells: bool,
+    /// Whether outputs are shown `"after"` (default) or `"before"` the source of their cell
+    #[serde(default)]
+    pub output_position: OutputPosition,
+    /// If non-empty, only cells carrying at least one of these tags are converted
+    #[serde(default)]
+    pub include_tags: Vec<String>,
+    /// Content shown in place of a notebook that fails to convert; `{path}` and `{error}` are
+    /// substituted (default `DEFAULT_ERROR_TEMPLATE`)
+    #[serde(default)]
+    pub error_template: Option<String>,
+    /// CSS `max-height` of the scroll box for outputs of cells marked `scrolled` (HTML renderer only)
+    #[serde(default = "default_scroll_max_height")]
+    pub scroll_max_height: String,
+    /// If true, put a `<style>` block for the emitted classes at the top of the first notebook
+    /// chapter and write it to the assets dir as `jupyter.css` (HTML renderer only)
+    #[serde(default)]
+    pub inject_css: bool,
+    /// If true, compute asset filenames and links but write nothing to disk
+    #[serde(default)]
+    pub dry_run: bool,
+    /// If true, rewrite `\(..\)` and `\[..\]` math delimiters in markdown cells to `$`/`$$`
+    #[serde(default)]
+    pub normalize_math: bool,
+    /// If true, show every representation of an output in priority order instead of only the first
+    #[serde(default)]
+    pub emit_all_mimes: bool,
+    /// Order in which `display_data` representations are preferred; the first one present is shown
+    #[serde(default = "default_mime_priority")]
+    pub mime_priority: Vec<String>,
+    /// Order in which `execute_result` representations are preferred; HTML comes first here
+    #[serde(default = "default_result_mime_priority")]
+    pub result_mime_priority: Vec<String>,
+    /// Renderer the converted markdown is destined for; set by the preprocessor
+    #[serde(skip)]
+    pub renderer: Renderer,
+}
+
+/// Where a code cell's outputs go relative to its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputPosition {
+    #[default]
+    After,
+    /// Outputs first, e.g. for "guess the code" exercises
+    Before,
+}
+
+/// Target mdbook renderer, used to decide whether raw HTML constructs are appropriate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Renderer {
+    #[default]
+    Html,
+    Markdown,
+}
+
+impl Renderer {
+    /// Maps an mdbook renderer name; anything other than `html` is treated as plain markdown
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "html" => Renderer::Html,
+            _ => Renderer::Markdown,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_scroll_max_height() -> String {
+    "24em".to_string()
+}
+
+fn default_language() -> String {
+    "python".to_string()
+}
+
+fn default_language_aliases() -> HashMap<String, String> {
+    [
+        ("ipython", "python"),
+        ("ipython2", "python"),
+        ("ipython3", "python"),
+        ("python2", "python"),
+        ("python3", "python"),
+        ("ir", "r"),
+        ("c++", "cpp"),
+        ("c#", "csharp"),
+        ("f#", "fsharp"),
+        ("octave", "matlab"),
+    ]
+    .into_iter()
+    .map(|(from, to)| (from.to_string(), to.to_string()))
+    .collect()
+}
+
+fn default_mime_priority() -> Vec<String> {
+    SUPPORTED_MIMES.iter().map(|mime| mime.to_string()).collect()
+}
+
+/// Like `default_mime_priority`, but with `text/html` first. Explicitly displayed outputs are
+/// mostly figures, where the image is the artifact, while a cell's value is often a table
+/// (e.g. a DataFrame) whose HTML rendering is what the author wants to show.
+fn default_result_mime_priority() -> Vec<String> {
+    let mut priority = default_mime_priority();
+    priority.retain(|mime| mime != "text/html");
+    priority.insert(0, "text/html".to_string());
+    priority
+}
+
+fn default_alt() -> String {
+    "Output of cell {cell}".to_string()
+}
+
+fn default_stream_name() -> String {
+    "stdout".to_string()
+}
+
+fn default_stderr_class() -> String {
+    "stderr".to_string()
+}
+
+fn default_error_class() -> String {
+    "jupyter-error".to_string()
 }
 
 impl Default for ConvertOptions {
     fn default() -> Self {
         ConvertOptions {
             embed_images: false,
+            default_alt: default_alt(),
+            default_language: default_language(),
+            unknown_language_fallback: None,
+            strip_ansi: true,
+            ansi_to_html: false,
+            stderr_class: default_stderr_class(),
+            error_class: default_error_class(),
+            fence_guard: true,
+            wrap_input: true,
+            wrap_output: true,
+            data_attributes: false,
+            group_cell: false,
+            render_html_output: true,
+            render_markdown_output: true,
+            max_output_lines: None,
+            max_image_width: None,
+            convert_images_to: None,
+            warn_out_of_order: false,
+            number_cells: false,
+            show_prompts: false,
+            parallel: true,
+            clean_assets: true,
+            collapse_output_over_lines: None,
+            render_interactive: false,
+            language_aliases: default_language_aliases(),
+            admonition_from_tags: false,
+            output_fence_lang: None,
+            output_no_copy: false,
+            keep_empty_cells: false,
+            strip_first_heading: false,
+            title_from_metadata: false,
+            use_img_dimensions: false,
+            inline_svg: false,
+            cell_markers: false,
+            respect_jupyter_collapse: true,
+            strip_magics: false,
+            comment_magics: false,
+            strip_patterns: Vec::new(),
+            cell_anchors: false,
+            emit_slide_markers: false,
+            allow_scripts: false,
+            assets_dir: None,
+            strict_nbformat: false,
+            inject_css: false,
+            scroll_max_height: default_scroll_max_height(),
+            error_template: None,
+            include_tags: Vec::new(),
+            output_position: OutputPosition::default(),
+            join_markdown_cells: false,
+            collapse_display_updates: true,
+            dry_run: false,
+            normalize_math: false,
+            emit_all_mimes: false,
+            mime_priority: default_mime_priority(),
+            result_mime_priority: default_result_mime_priority(),
+            renderer: Renderer::default(),
         }
     }
 }
@@ -25,19 +357,81 @@ impl Default for ConvertOptions {
 #[derive(Debug, Deserialize)]
 pub struct Notebook {
     pub cells: Vec<Cell>,
-    // other fields (metadata, nbformat, nbformat_minor) are ignored for now
+    #[serde(default)]
+    pub metadata: NotebookMetadata,
+    /// Major format version; nbformat 3 documents are upgraded to the v4 shape before deserializing
+    #[serde(default, deserialize_with = "format_number")]
+    pub nbformat: Option<u64>,
+    #[serde(default, deserialize_with = "format_number")]
+    pub nbformat_minor: Option<u64>,
+}
+
+/// A format version that isn't a non-negative integer is treated as missing
+fn format_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Ok(Value::deserialize(deserializer)?.as_u64())
+}
+
+impl Notebook {
+    /// Kernel language from `kernelspec.language`, falling back to `language_info.name`
+    pub fn language(&self) -> Option<&str> {
+        self.metadata
+            .kernelspec
+            .as_ref()
+            .and_then(|k| k.language.as_deref())
+            .or_else(|| self.metadata.language_info.as_ref().and_then(|l| l.name.as_deref()))
+    }
+}
+
+/// Notebook-level metadata; only the fields the converter uses are modelled
+#[derive(Debug, Default, Deserialize)]
+pub struct NotebookMetadata {
+    pub kernelspec: Option<KernelSpec>,
+    pub language_info: Option<LanguageInfo>,
+    /// Page title as stored by Jupyter Book
+    pub title: Option<String>,
+    /// Per-notebook overrides of the book-wide options
+    #[serde(rename = "mdbook-jupyter")]
+    pub mdbook_jupyter: Option<NotebookOverrides>,
+}
+
+/// Options a notebook can set in `metadata.mdbook-jupyter`; these win over book.toml
+#[derive(Debug, Default, Deserialize)]
+pub struct NotebookOverrides {
+    pub embed_images: Option<bool>,
+}
+
+impl NotebookOverrides {
+    /// Merges the overrides over the global options
+    fn apply(&self, options: &mut ConvertOptions) {
+        if let Some(embed_images) = self.embed_images {
+            options.embed_images = embed_images;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KernelSpec {
+    pub language: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LanguageInfo {
+    pub name: Option<String>,
 }
 #[derive(Debug, Deserialize)]
 #[serde(tag = "cell_type")]
 pub enum Cell {
     #[serde(rename = "markdown")]
     Markdown {
+        #[serde(default, deserialize_with = "nullable_source")]
         source: MultilineString,
         metadata: Option<Value>,
+        attachments: Option<Map<String, Value>>,
     },
 
     #[serde(rename = "code")]
     Code {
+        #[serde(default, deserialize_with = "nullable_source")]
         source: MultilineString,
         outputs: Vec<Output>,
         execution_count: Option<u32>,
@@ -46,28 +440,146 @@ pub enum Cell {
 
     #[serde(rename = "raw")]
     Raw {
+        #[serde(default, deserialize_with = "nullable_source")]
         source: MultilineString,
         metadata: Option<Value>,
     },
+
+    /// Any cell type this converter doesn't model (e.g. from a newer nbformat or an extension)
+    #[serde(other)]
+    Unknown,
+}
+
+impl Cell {
+    fn metadata(&self) -> Option<&Value> {
+        match self {
+            Cell::Markdown { metadata, .. }
+            | Cell::Code { metadata, .. }
+            | Cell::Raw { metadata, .. } => metadata.as_ref(),
+            Cell::Unknown => None,
+        }
+    }
+
+    /// The nbformat `cell_type` name
+    fn kind(&self) -> &'static str {
+        match self {
+            Cell::Markdown { .. } => "markdown",
+            Cell::Code { .. } => "code",
+            Cell::Raw { .. } => "raw",
+            Cell::Unknown => "unknown",
+        }
+    }
+
+    /// `metadata.slideshow.slide_type`, as set by the Jupyter slideshow toolbar
+    fn slide_type(&self) -> Option<&str> {
+        self.metadata()
+            .and_then(|m| m.get("slideshow"))
+            .and_then(|s| s.get("slide_type"))
+            .and_then(|t| t.as_str())
+    }
+
+    /// JupyterLab collapse state, `metadata.jupyter.source_hidden` or `outputs_hidden`
+    fn jupyter_hidden(&self, key: &str) -> bool {
+        self.metadata()
+            .and_then(|m| m.get("jupyter"))
+            .and_then(|j| j.get(key))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Alt text for the cell's images from `metadata.mdbook-jupyter.alt`, or its `caption`
+    fn image_alt(&self) -> Option<&str> {
+        let settings = self.metadata()?.get("mdbook-jupyter")?;
+        settings
+            .get("alt")
+            .or_else(|| settings.get("caption"))
+            .and_then(|v| v.as_str())
+    }
+
+    /// Tags from `metadata.tags`; a missing or non-array value means no tags
+    fn tags(&self) -> Vec<&str> {
+        self.metadata()
+            .and_then(|m| m.get("tags"))
+            .and_then(|t| t.as_array())
+            .map(|arr| arr.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default()
+    }
 }
 
 /// MultilineString captures the fact that many fields in nbformat
 /// may be a single string or an array of strings.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum MultilineString {
     Single(String),
     Multi(Vec<String>),
 }
 
+impl Default for MultilineString {
+    fn default() -> Self {
+        MultilineString::Single(String::new())
+    }
+}
+
+/// Converts CRLF line endings to LF and drops any other carriage return
+fn normalize_newlines(s: String) -> String {
+    if !s.contains('\r') {
+        return s;
+    }
+    s.replace("\r\n", "\n").replace('\r', "")
+}
+
+/// Renders stream text the way a terminal shows it: a lone `\r` returns to the start of the line,
+/// so progress bars that redraw themselves keep only their final state. Applied after
+/// consecutive stream chunks are merged, since a redraw often starts a new chunk.
+fn terminal_text(s: String) -> String {
+    if !s.contains('\r') {
+        return s;
+    }
+
+    s.replace("\r\n", "\n")
+        .split_inclusive('\n')
+        .map(|line| {
+            let (line, newline) = match line.strip_suffix('\n') {
+                Some(line) => (line, "\n"),
+                None => (line, ""),
+            };
+            // A trailing `\r` hasn't been overwritten yet, so the text before it still shows
+            let line = line.trim_end_matches('\r');
+            let visible = line.rsplit('\r').next().unwrap_or(line);
+            format!("{}{}", visible, newline)
+        })
+        .collect()
+}
+
+/// Reads a cell `source` that hand-edited or generated notebooks may leave `null`; it counts as empty
+fn nullable_source<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MultilineString, D::Error> {
+    Ok(Option::<MultilineString>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 impl MultilineString {
-    fn into_string(self) -> String {
+    /// Joins the text as stored, carriage returns included
+    fn join(self) -> String {
         match self {
             MultilineString::Single(s) => s,
             MultilineString::Multi(v) => v.join("")
         }
     }
 
+    /// Joins the text with LF line endings
+    fn into_string(self) -> String {
+        normalize_newlines(self.join())
+    }
+
+    /// Joins cell source, dropping exactly one trailing newline so fences close tightly
+    fn into_source(self) -> String {
+        let mut s = self.into_string();
+        if s.ends_with('\n') {
+            s.pop();
+        }
+        s
+    }
+
     // fn as_string(&self) -> String {
     //     match self {
     //         MultilineString::Single(s) => s.clone(),
@@ -81,40 +593,192 @@ impl MultilineString {
             MultilineString::Multi(v) => v.iter().map(|s| s.len()).sum()
         }
     }
+
+    fn line_count(&self) -> usize {
+        match self {
+            MultilineString::Single(s) => s.lines().count(),
+            MultilineString::Multi(v) => v.iter().map(|s| s.lines().count()).sum()
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "output_type")]
 pub enum Output {
+    /// Older notebooks may omit `name`; such streams are treated as stdout
     #[serde(rename = "stream")]
-    Stream { name: Option<String>, text: MultilineString },
+    Stream {
+        #[serde(default = "default_stream_name")]
+        name: String,
+        text: MultilineString,
+    },
 
+    /// `transient` holds the `display_id` that `update_display` targets
     #[serde(rename = "display_data")]
-    DisplayData { data: Map<String, Value>, metadata: Option<Value> },
+    DisplayData { data: Map<String, Value>, metadata: Option<Value>, transient: Option<Value> },
 
     #[serde(rename = "execute_result")]
     ExecuteResult { data: Map<String, Value>, metadata: Option<Value>, execution_count: Option<u32> },
 
     #[serde(rename = "error")]
     Error { ename: String, evalue: String, traceback: MultilineString },
+
+    /// Any output type this converter doesn't model
+    #[serde(other)]
+    Unknown,
+}
+
+impl Output {
+    /// `transient.display_id` of a `display_data` output
+    fn display_id(&self) -> Option<&str> {
+        match self {
+            Output::DisplayData { transient: Some(transient), .. } => {
+                transient.get("display_id").and_then(Value::as_str)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Output of a notebook conversion
+#[derive(Debug, Clone)]
+pub struct ConvertResult {
+    pub markdown: String,
+    /// Number of asset files written to the assets directory
+    pub assets_written: usize,
+    /// Every asset file the markdown links to, in order of first reference
+    pub assets: Vec<AssetInfo>,
+}
+
+/// An asset file referenced by converted markdown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetInfo {
+    pub path: PathBuf,
+    /// Size of the asset in bytes
+    pub size: usize,
+    /// False when an identical file already existed, true when it was (or in a dry run would be) written
+    pub written: bool,
 }
 
 /// Converts a Jupyter notebook to Markdown format
 pub fn convert_notebook_to_md(path: &Path, assets_out: &Path) -> Result<String> {
     let options = ConvertOptions::default();
-    convert_notebook_to_md_with_options(path, assets_out, options)
+    let book_path = path.file_name().map(Path::new).unwrap_or(path);
+    convert_notebook_to_md_with_options(path, book_path, assets_out, options).map(|result| result.markdown)
+}
+
+/// Converts a Jupyter notebook to Markdown format with custom options.
+/// `book_path` is the notebook's path relative to the book source, used to link assets from its page.
+pub fn convert_notebook_to_md_with_options(path: &Path, book_path: &Path, assets_out: &Path, options: ConvertOptions) -> Result<ConvertResult> {
+    let bytes = fs::read(path).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    convert_notebook_bytes(&bytes, path, book_path, assets_out, options)
+}
+
+/// Converts notebook JSON that is already in memory.
+/// `book_path` names the notebook in errors and decides how its page links to assets.
+pub fn convert_notebook_str(json: &str, book_path: &Path, assets_out: &Path, options: ConvertOptions) -> Result<ConvertResult> {
+    convert_notebook_bytes(json.as_bytes(), book_path, book_path, assets_out, options)
+}
+
+/// Converts notebook JSON read from `reader`.
+/// `book_path` names the notebook in errors and decides how its page links to assets.
+pub fn convert_notebook_reader<R: Read>(mut reader: R, book_path: &Path, assets_out: &Path, options: ConvertOptions) -> Result<ConvertResult> {
+    // The whole document is needed anyway, and keeping the bytes allows better parse errors
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(|source| ConvertError::Io {
+        path: book_path.to_path_buf(),
+        source,
+    })?;
+    convert_notebook_bytes(&bytes, book_path, book_path, assets_out, options)
+}
+
+/// Converts a notebook like `convert_notebook_to_md_with_options`, but writes the markdown to
+/// `writer` one cell at a time instead of collecting it, so huge notebooks don't have to fit in
+/// memory twice. Returns the assets the markdown links to.
+pub fn convert_notebook_to_writer<W: Write>(
+    path: &Path,
+    book_path: &Path,
+    assets_out: &Path,
+    options: ConvertOptions,
+    writer: W,
+) -> Result<Vec<AssetInfo>> {
+    let bytes = fs::read(path).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut sink = WriterSink(writer);
+    let assets = convert_notebook_into(&bytes, path, book_path, assets_out, options, &mut sink)?;
+    sink.0.flush().map_err(|source| ConvertError::Write { source })?;
+    Ok(assets)
+}
+
+/// Destination of converted markdown, fed one cell at a time
+trait MarkdownSink {
+    /// Called before the first cell with an estimate of the total length
+    fn reserve(&mut self, _additional: usize) {}
+
+    fn push(&mut self, chunk: &str) -> Result<()>;
+}
+
+impl MarkdownSink for String {
+    fn reserve(&mut self, additional: usize) {
+        String::reserve(self, additional);
+    }
+
+    fn push(&mut self, chunk: &str) -> Result<()> {
+        self.push_str(chunk);
+        Ok(())
+    }
+}
+
+struct WriterSink<W>(W);
+
+impl<W: Write> MarkdownSink for WriterSink<W> {
+    fn push(&mut self, chunk: &str) -> Result<()> {
+        self.0
+            .write_all(chunk.as_bytes())
+            .map_err(|source| ConvertError::Write { source })
+    }
 }
 
-/// Converts a Jupyter notebook to Markdown format with custom options
-pub fn convert_notebook_to_md_with_options(path: &Path, assets_out: &Path, options: ConvertOptions) -> Result<String> {
-    let file = File::open(path)?;
-    let notebook: Notebook = serde_json::from_reader(file)?;
+/// Converts notebook JSON into a markdown string; `path` is only used to describe the notebook in errors
+fn convert_notebook_bytes(bytes: &[u8], path: &Path, book_path: &Path, assets_out: &Path, options: ConvertOptions) -> Result<ConvertResult> {
+    let mut markdown = String::new();
+    let assets = convert_notebook_into(bytes, path, book_path, assets_out, options, &mut markdown)?;
+    Ok(ConvertResult {
+        markdown,
+        assets_written: assets.iter().filter(|asset| asset.written).count(),
+        assets,
+    })
+}
+
+/// Shared conversion core writing into `sink`; `path` is only used to describe the notebook in errors
+fn convert_notebook_into(
+    bytes: &[u8],
+    path: &Path,
+    book_path: &Path,
+    assets_out: &Path,
+    options: ConvertOptions,
+    sink: &mut impl MarkdownSink,
+) -> Result<Vec<AssetInfo>> {
+    let notebook = parse_notebook(bytes, path, options.strict_nbformat)?;
+
+    let mut options = options;
+    if let Some(overrides) = &notebook.metadata.mdbook_jupyter {
+        overrides.apply(&mut options);
+    }
 
     // Ensure assets directory exists (only needed if not embedding images)
-    if !options.embed_images {
-        if let Err(e) = create_dir_all(assets_out) {
+    if !options.embed_images && !options.dry_run {
+        if let Err(source) = create_dir_all(assets_out) {
             // If we cannot create the assets directory, return an error
-            return Err(anyhow::anyhow!(e));
+            return Err(ConvertError::AssetWrite {
+                path: assets_out.to_path_buf(),
+                source,
+            });
         }
     }
 
@@ -124,178 +788,2737 @@ pub fn convert_notebook_to_md_with_options(path: &Path, assets_out: &Path, optio
         .iter()
         .map(|c| estimate_cell_len(c))
         .sum();
+    sink.reserve(est);
 
-    let mut md = String::with_capacity(est);
+    // Cells are rendered into this buffer and handed to the sink one at a time
+    let mut md = String::new();
 
-    // counter for generating unique asset filenames
-    let mut asset_counter: u32 = 0;
+    // links climb out of the chapter's directory so nested pages resolve them
+    let mut assets = AssetWriter::new(assets_out, book_path, &assets_link_dir(assets_out, &options), options.dry_run);
 
-    for cell in notebook.cells.into_iter() {
-        process_cell(&mut md, cell, assets_out, &mut asset_counter, &options)?;
-    }
-
-    Ok(md)
-}
+    let language = notebook
+        .language()
+        .unwrap_or(options.default_language.as_str())
+        .to_string();
 
-fn estimate_cell_len(cell: &Cell) -> usize {
-    match cell {
-        Cell::Markdown { source, .. } => source.len() + 4,
-        Cell::Raw { source, .. } => source.len() + 4,
-        Cell::Code { source, outputs, .. } => {
-            let src_len = source.len() + 12; // fenced code block overhead
-            let outputs_len: usize = outputs.iter().map(|o| estimate_output_len(o)).sum();
-            src_len + outputs_len
+    let mut cells = notebook.cells;
+    if options.strip_first_heading {
+        // mdbook already titles the chapter from SUMMARY.md, so a leading `# Title` would duplicate it
+        if let Some(Cell::Markdown { source, .. }) = cells.first_mut() {
+            let text = std::mem::replace(source, MultilineString::Single(String::new())).into_string();
+            *source = MultilineString::Single(strip_leading_h1(&text).unwrap_or(text));
         }
     }
-}
 
-fn estimate_output_len(output: &Output) -> usize {
-    match output {
-        Output::Stream { text, .. } => text.len() + 8,
-        Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
-            // Pick the first textual value we might include (handle arrays/objects)
-            if let Some(s) = data.get("text/markdown").and_then(|v| value_to_text(v)) {
-                s.len() + 4
-            } else if let Some(s) = data.get("text/plain").and_then(|v| value_to_text(v)) {
-                s.len() + 8
-            } else if let Some(s) = data.get("image/png").and_then(|v| value_to_text(v)) {
-                s.len() + 32
-            } else {
-                16
+    // Runs after strip_first_heading, so with both set the metadata title replaces the notebook's
+    if options.title_from_metadata {
+        let has_heading = match cells.first() {
+            Some(Cell::Markdown { source, .. }) => starts_with_heading(&source.clone().into_string()),
+            _ => false,
+        };
+        if let Some(title) = notebook.metadata.title.as_deref().map(str::trim).filter(|t| !t.is_empty()) {
+            if !has_heading {
+                md.push_str(&format!("# {}\n\n", title));
             }
         }
-        Output::Error { traceback, .. } => traceback.len() + 16,
     }
-}
 
-fn value_to_text(value: &Value) -> Option<String> {
-    match value {
-        Value::String(s) => Some(s.clone()),
-        Value::Array(arr) => {
-            let mut out = String::new();
-            for v in arr.iter() {
-                if let Some(s) = value_to_text(v) {
-                    out.push_str(&s);
-                }
+    let strip_patterns: Vec<Regex> = options
+        .strip_patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                warn!("Ignoring invalid strip pattern '{}': {}", pattern, e);
+                None
+            }
+        })
+        .collect();
+    // strip_magics wins over comment_magics when both are set
+    let fence_language = options.language_aliases.get(&language).unwrap_or(&language);
+    let magics = if options.strip_magics {
+        MagicLines::Strip
+    } else if options.comment_magics {
+        MagicLines::Comment(comment_token(fence_language))
+    } else {
+        MagicLines::Keep
+    };
+    if !matches!(magics, MagicLines::Keep) || !strip_patterns.is_empty() {
+        for cell in cells.iter_mut() {
+            if let Cell::Code { source, .. } = cell {
+                let text = std::mem::take(source).into_string();
+                *source = MultilineString::Single(strip_source_lines(&text, magics, &strip_patterns));
             }
-            Some(out)
         }
-        Value::Number(n) => Some(n.to_string()),
-        Value::Object(o) => serde_json::to_string(o).ok(),
-        Value::Bool(b) => Some(b.to_string()),
-        Value::Null => None,
     }
-}
 
-fn process_cell(md: &mut String, cell: Cell, assets_out: &Path, counter: &mut u32, options: &ConvertOptions) -> Result<(), anyhow::Error> {
-    match cell {
-        Cell::Markdown { source, .. } => {
-            md.push_str(&source.into_string());
-            md.push_str("\n\n");
+    if options.collapse_display_updates {
+        drop_stale_displays(&mut cells);
+    }
+
+    if options.warn_out_of_order && !executed_in_order(&cells) {
+        warn!(
+            "Notebook '{}' was executed out of order, so it may not reproduce when run top to bottom",
+            path.display()
+        );
+    }
+
+    let mut empty = md.trim().is_empty();
+    sink.push(&md)?;
+    md.clear();
+
+    // Code cells are numbered in the order they appear, regardless of execution counts; cells
+    // that are dropped don't take a number
+    let mut code_cells = 0;
+    let mut previous_markdown = false;
+    for (index, cell) in cells.into_iter().enumerate() {
+        // Joined markdown cells are held back in the buffer and separated by a single line break,
+        // so a list or table split across cells stays one block
+        let markdown = matches!(cell, Cell::Markdown { .. });
+        let join = options.join_markdown_cells && markdown;
+        if join && previous_markdown && md.ends_with("\n\n") {
+            md.pop();
         }
-        Cell::Code { source, outputs, .. } => {
-            md.push_str("```python\n");
-            md.push_str(&source.into_string());
-            md.push_str("\n```\n\n");
 
-            for output in outputs.into_iter() {
-                process_output(md, output, assets_out, counter, options)?;
-            }
+        let code = matches!(cell, Cell::Code { .. });
+        let number = (options.number_cells && code).then_some(code_cells + 1);
+        let emitted = process_cell(&mut md, cell, index, number, &language, &mut assets, &options).map_err(|e| ConvertError::Cell {
+            index,
+            source: Box::new(e),
+        })?;
+        if code && emitted {
+            code_cells += 1;
         }
-        Cell::Raw { source, .. } => {
-            md.push_str(&source.into_string());
-            md.push_str("\n\n");
+        previous_markdown = markdown;
+        if !join {
+            empty &= md.trim().is_empty();
+            sink.push(&md)?;
+            md.clear();
         }
     }
+    empty &= md.trim().is_empty();
+    sink.push(&md)?;
 
-    Ok(())
+    // An empty chapter gives no hint why it is blank, so say so in the log and the page source
+    if empty {
+        warn!("Notebook '{}' produced no content", path.display());
+        sink.push("<!-- mdbook-jupyter: this notebook produced no content -->\n")?;
+    }
+
+    Ok(assets.assets)
 }
 
-fn process_output(md: &mut String, output: Output, assets_out: &Path, counter: &mut u32, options: &ConvertOptions) -> Result<(), anyhow::Error> {
-    match output {
-        Output::Stream { text, .. } => {
-            md.push_str("```\n");
-            md.push_str(&text.into_string());
-            md.push_str("\n```\n\n");
-        }
-        Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
-            // Handle common image types first; values may be strings or arrays of strings
-            if let Some(img_b64) = data.get("image/png").and_then(|v| value_to_text(v)) {
-                if options.embed_images {
-                    // Embed image as base64 data URL
-                    md.push_str(&format!("![output image](data:image/png;base64,{})\n\n", img_b64));
-                } else {
-                    // decode and write to file
-                    let decoded = STANDARD.decode(&img_b64)?;
-                    let filename = format!("output_{:03}.png", *counter);
-                    let out_path = assets_out.join(&filename);
-                    fs::write(&out_path, &decoded)?;
-                    *counter += 1;
-
-                    if let Some(dirname) = assets_out.file_name().map(|s| s.to_string_lossy()) {
-                        md.push_str(&format!("![output image]({}/{})\n\n", dirname, filename));
-                    } else {
-                        md.push_str(&format!("![output image]({})\n\n", filename));
-                    }
-                }
-            } else if let Some(img_b64) = data.get("image/jpeg").and_then(|v| value_to_text(v)) {
-                if options.embed_images {
-                    // Embed image as base64 data URL
-                    md.push_str(&format!("![output image](data:image/jpeg;base64,{})\n\n", img_b64));
-                } else {
-                    let decoded = STANDARD.decode(&img_b64)?;
-                    let filename = format!("output_{:03}.jpg", *counter);
-                    let out_path = assets_out.join(&filename);
-                    fs::write(&out_path, &decoded)?;
-                    *counter += 1;
-
-                    if let Some(dirname) = assets_out.file_name().map(|s| s.to_string_lossy()) {
-                        md.push_str(&format!("![output image]({}/{})\n\n", dirname, filename));
-                    } else {
-                        md.push_str(&format!("![output image]({})\n\n", filename));
-                    }
-                }
-            } else if let Some(svg) = data.get("image/svg+xml").and_then(|v| value_to_text(v)) {
-                if options.embed_images {
-                    // Embed SVG as base64 data URL
-                    let svg_b64 = STANDARD.encode(&svg);
-                    md.push_str(&format!("![output svg](data:image/svg+xml;base64,{})\n\n", svg_b64));
-                } else {
-                    let filename = format!("output_{:03}.svg", *counter);
-                    let out_path = assets_out.join(&filename);
-                    fs::write(&out_path, svg.as_bytes())?;
-                    *counter += 1;
+/// Placeholder shown in place of a notebook that fails to convert: `{path}` is the notebook and
+/// `{error}` the message as a code block
+pub const DEFAULT_ERROR_TEMPLATE: &str = "> **Notebook conversion failed** for `{path}`\n\n{error}\n\nPlease check the original notebook and converter logs for details.";
 
-                    if let Some(dirname) = assets_out.file_name().map(|s| s.to_string_lossy()) {
-                        md.push_str(&format!("![output svg]({}/{})\n\n", dirname, filename));
-                    } else {
-                        md.push_str(&format!("![output svg]({})\n\n", filename));
-                    }
-                }
-            } else if let Some(mdtext) = data.get("text/markdown").and_then(|v| value_to_text(v)) {
-                md.push_str(&mdtext);
-                md.push_str("\n\n");
-            } else if let Some(text) = data.get("text/plain").and_then(|v| value_to_text(v)) {
-                md.push_str("```\n");
-                md.push_str(&text);
-                md.push_str("\n```\n\n");
-            } else if let Some(html) = data.get("text/html").and_then(|v| value_to_text(v)) {
-                md.push_str("```html\n");
-                md.push_str(&html);
-                md.push_str("\n```\n\n");
+/// Chapter content for a notebook that failed to convert, from `error_template`. The error goes
+/// in a fence longer than any backtick run in it; with the HTML renderer the placeholder is
+/// wrapped in the `error_class` container.
+pub fn error_placeholder(path: &Path, error: &str, options: &ConvertOptions) -> String {
+    let template = options.error_template.as_deref().unwrap_or(DEFAULT_ERROR_TEMPLATE);
+    let mut fenced = String::new();
+    push_fence(&mut fenced, &fence_for(error), "", error);
+
+    // Substituted in one pass so an error message mentioning `{path}` is left alone
+    let path = path.display().to_string();
+    let body = template
+        .split("{error}")
+        .map(|part| part.replace("{path}", &path))
+        .collect::<Vec<_>>()
+        .join(fenced.trim_end());
+
+    let mut md = String::from("<!-- mdbook-jupyter: conversion error -->\n\n");
+    if options.renderer == Renderer::Html {
+        // The closure can't fail
+        let _ = wrap_in_div(&mut md, &options.error_class, |md| {
+            md.push_str(&body);
+            md.push_str("\n\n");
+            Ok(())
+        });
+    } else {
+        md.push_str(&body);
+        md.push('\n');
+    }
+    md
+}
+
+/// Just the cells of a notebook, enough to find its title without decoding any outputs
+#[derive(Deserialize)]
+struct TitleNotebook {
+    cells: Option<Vec<TitleCell>>,
+}
+
+#[derive(Deserialize)]
+struct TitleCell {
+    #[serde(default)]
+    cell_type: String,
+    source: Option<MultilineString>,
+}
+
+/// Title of a notebook: the first level-1 heading of its first markdown cell
+pub fn notebook_title(path: &Path) -> Result<Option<String>> {
+    let bytes = fs::read(path).map_err(|source| ConvertError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let notebook: TitleNotebook = serde_json::from_slice(&bytes).map_err(|e| parse_error(path, &bytes, e))?;
+
+    let markdown = match notebook.cells {
+        Some(cells) => cells
+            .into_iter()
+            .find(|cell| cell.cell_type == "markdown")
+            .map(|cell| cell.source.unwrap_or_default().into_string()),
+        // nbformat 3 keeps its cells in worksheets; those notebooks go through the full upgrade
+        None => parse_notebook(&bytes, path, false)?.cells.into_iter().find_map(|cell| match cell {
+            Cell::Markdown { source, .. } => Some(source.into_string()),
+            _ => None,
+        }),
+    };
+
+    let title = markdown
+        .and_then(|text| {
+            text.lines()
+                .find_map(|line| line.trim_start().strip_prefix("# ").map(|t| t.trim().to_string()))
+        });
+    Ok(title)
+}
+
+/// Only the format version, read before deciding how to deserialize the document
+#[derive(Deserialize)]
+struct FormatVersion {
+    #[serde(default)]
+    nbformat: Value,
+}
+
+/// Parses notebook JSON, upgrading nbformat 3 documents to the v4 shape first. Other versions
+/// than 3 and 4 are read as v4 with a warning, or rejected if `strict`.
+fn parse_notebook(bytes: &[u8], path: &Path, strict: bool) -> Result<Notebook> {
+    let version: FormatVersion = serde_json::from_slice(bytes).map_err(|e| parse_error(path, bytes, e))?;
+    match version.nbformat.as_u64() {
+        Some(3) => {}
+        Some(4) => return serde_json::from_slice(bytes).map_err(|e| parse_error(path, bytes, e)),
+        _ => {
+            let found = match &version.nbformat {
+                Value::Null => "no nbformat version".to_string(),
+                other => format!("nbformat {}", other),
+            };
+            if strict {
+                return Err(ConvertError::UnsupportedFormat {
+                    path: path.to_path_buf(),
+                    found,
+                });
             }
+            warn!("'{}' has {}; converting it as nbformat 4", path.display(), found);
+            return serde_json::from_slice(bytes).map_err(|e| parse_error(path, bytes, e));
         }
-        Output::Error { ename, evalue, traceback } => {
-            md.push_str("```error\n");
-            md.push_str(&ename);
-            md.push_str(": ");
-            md.push_str(&evalue);
-            md.push_str("\n");
-            md.push_str(&traceback.into_string());
-            md.push_str("\n```\n\n");
+    }
+
+    let mut value: Value = serde_json::from_slice(bytes).map_err(|e| parse_error(path, bytes, e))?;
+    upgrade_v3(&mut value);
+    Notebook::deserialize(value).map_err(|e| parse_error(path, bytes, e))
+}
+
+/// nbformat 3 output keys and the mime types they became in v4
+const V3_MIME_KEYS: [(&str, &str); 9] = [
+    ("text", "text/plain"),
+    ("html", "text/html"),
+    ("markdown", "text/markdown"),
+    ("latex", "text/latex"),
+    ("png", "image/png"),
+    ("jpeg", "image/jpeg"),
+    ("svg", "image/svg+xml"),
+    ("json", "application/json"),
+    ("javascript", "application/javascript"),
+];
+
+/// Rewrites an nbformat 3 document into the v4 shape: cells move out of `worksheets`,
+/// code cells use `source`/`execution_count` and outputs carry their mime bundle in `data`.
+fn upgrade_v3(notebook: &mut Value) {
+    let Some(obj) = notebook.as_object_mut() else {
+        return;
+    };
+
+    let mut cells = Vec::new();
+    if let Some(Value::Array(worksheets)) = obj.remove("worksheets") {
+        for mut worksheet in worksheets {
+            if let Some(Value::Array(ws_cells)) = worksheet.get_mut("cells").map(Value::take) {
+                cells.extend(ws_cells);
+            }
         }
     }
+    cells.iter_mut().for_each(upgrade_v3_cell);
 
+    // v3 records the language on each code cell rather than in the notebook metadata
+    let cell_language = cells
+        .iter()
+        .find_map(|c| c.get("language").and_then(Value::as_str))
+        .map(str::to_string);
+    if let Some(Value::Object(metadata)) = obj.get_mut("metadata") {
+        if !metadata.contains_key("kernelspec") && !metadata.contains_key("language_info") {
+            if let Some(name) = cell_language {
+                let mut info = Map::new();
+                info.insert("name".to_string(), Value::String(name));
+                metadata.insert("language_info".to_string(), Value::Object(info));
+            }
+        }
+    }
+
+    obj.insert("cells".to_string(), Value::Array(cells));
+}
+
+fn upgrade_v3_cell(cell: &mut Value) {
+    let Some(obj) = cell.as_object_mut() else {
+        return;
+    };
+
+    let kind = obj.get("cell_type").and_then(Value::as_str).unwrap_or_default().to_string();
+    match kind.as_str() {
+        "code" => {
+            if let Some(input) = obj.remove("input") {
+                obj.insert("source".to_string(), input);
+            }
+            if let Some(count) = obj.remove("prompt_number") {
+                obj.insert("execution_count".to_string(), count);
+            }
+            if let Some(Value::Array(outputs)) = obj.get_mut("outputs") {
+                outputs.iter_mut().for_each(upgrade_v3_output);
+            }
+        }
+        // Heading cells were folded into markdown in v4
+        "heading" => {
+            let level = obj.get("level").and_then(Value::as_u64).unwrap_or(1).clamp(1, 6) as usize;
+            let text = obj.get("source").and_then(value_to_text).unwrap_or_default();
+            obj.insert("cell_type".to_string(), Value::String("markdown".to_string()));
+            obj.insert("source".to_string(), Value::String(format!("{} {}", "#".repeat(level), text)));
+        }
+        _ => {}
+    }
+}
+
+fn upgrade_v3_output(output: &mut Value) {
+    let Some(obj) = output.as_object_mut() else {
+        return;
+    };
+
+    let kind = obj.get("output_type").and_then(Value::as_str).unwrap_or_default().to_string();
+    match kind.as_str() {
+        "stream" => {
+            if let Some(name) = obj.remove("stream") {
+                obj.insert("name".to_string(), name);
+            }
+        }
+        "pyerr" => {
+            obj.insert("output_type".to_string(), Value::String("error".to_string()));
+        }
+        "pyout" | "display_data" => {
+            if kind == "pyout" {
+                obj.insert("output_type".to_string(), Value::String("execute_result".to_string()));
+                if let Some(count) = obj.remove("prompt_number") {
+                    obj.insert("execution_count".to_string(), count);
+                }
+            }
+
+            let mut data = Map::new();
+            for (key, mime) in V3_MIME_KEYS {
+                if let Some(value) = obj.remove(key) {
+                    data.insert(mime.to_string(), value);
+                }
+            }
+            obj.insert("data".to_string(), Value::Object(data));
+        }
+        _ => {}
+    }
+}
+
+/// What happens to the IPython magic (`%`, `%%`) and shell (`!`) lines at the top of a code cell
+#[derive(Debug, Clone, Copy)]
+enum MagicLines<'a> {
+    Keep,
+    Strip,
+    /// Prefix them with the language's comment token
+    Comment(&'a str),
+}
+
+/// Drops `display_data` outputs that a later output with the same `transient.display_id`
+/// supersedes, so only the final state of an updated display is shown
+fn drop_stale_displays(cells: &mut [Cell]) {
+    let mut last = HashMap::new();
+    for (cell_index, cell) in cells.iter().enumerate() {
+        if let Cell::Code { outputs, .. } = cell {
+            for (output_index, output) in outputs.iter().enumerate() {
+                if let Some(id) = output.display_id() {
+                    last.insert(id.to_string(), (cell_index, output_index));
+                }
+            }
+        }
+    }
+    if last.is_empty() {
+        return;
+    }
+
+    for (cell_index, cell) in cells.iter_mut().enumerate() {
+        if let Cell::Code { outputs, .. } = cell {
+            let mut output_index = 0;
+            outputs.retain(|output| {
+                let keep = output
+                    .display_id()
+                    .is_none_or(|id| last[id] == (cell_index, output_index));
+                output_index += 1;
+                keep
+            });
+        }
+    }
+}
+
+/// Handles the leading magic lines as `magics` says, then drops every line matching one of
+/// `patterns`. Later `%` lines are kept, they may well be Python.
+fn strip_source_lines(source: &str, magics: MagicLines, patterns: &[Regex]) -> String {
+    let mut leading = true;
+    source
+        .split_inclusive('\n')
+        .filter_map(|line| {
+            let trimmed = line.trim_start();
+            leading = leading && (trimmed.starts_with('%') || trimmed.starts_with('!'));
+            let line: Cow<str> = match magics {
+                MagicLines::Strip if leading => return None,
+                MagicLines::Comment(token) if leading => format!("{} {}", token, line).into(),
+                _ => line.into(),
+            };
+
+            let text = line.trim_end_matches(['\r', '\n']);
+            (!patterns.iter().any(|re| re.is_match(text))).then_some(line)
+        })
+        .collect()
+}
+
+/// Line comment token of a (fence) language; `#` unless the language is known to differ
+fn comment_token(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "c" | "cpp" | "c++" | "csharp" | "cs" | "go" | "java" | "javascript" | "js" | "kotlin" | "rust" | "scala"
+        | "swift" | "typescript" | "ts" => "//",
+        "haskell" | "lua" | "sql" => "--",
+        "matlab" | "octave" => "%",
+        _ => "#",
+    }
+}
+
+/// True when the code cells' execution counts are strictly ascending; unexecuted cells are ignored
+fn executed_in_order(cells: &[Cell]) -> bool {
+    let counts: Vec<u32> = cells
+        .iter()
+        .filter_map(|cell| match cell {
+            Cell::Code { execution_count, .. } => *execution_count,
+            _ => None,
+        })
+        .collect();
+    counts.windows(2).all(|pair| pair[0] < pair[1])
+}
+
+/// True when the first non-empty line of `text` is an ATX heading of any level
+fn starts_with_heading(text: &str) -> bool {
+    text.lines()
+        .find(|line| !line.trim().is_empty())
+        .is_some_and(|line| line.trim_start().starts_with('#'))
+}
+
+/// Removes the first non-empty line when it is a level-1 ATX heading (`# Title`).
+/// Returns `None` when the text doesn't start with such a heading.
+fn strip_leading_h1(text: &str) -> Option<String> {
+    let mut lines = text.split_inclusive('\n');
+    let heading = lines.by_ref().find(|line| !line.trim().is_empty())?;
+
+    let heading = heading.trim_start();
+    if !(heading.starts_with("# ") || heading.trim_end() == "#") {
+        return None;
+    }
+
+    let rest: String = lines.collect();
+    Some(rest.trim_start_matches(['\r', '\n']).to_string())
+}
+
+/// Builds a parse error carrying the notebook path and location. When the failure is caused by
+/// a cell or output without a `cell_type`/`output_type`, the message names the offending cell.
+fn parse_error(path: &Path, bytes: &[u8], source: serde_json::Error) -> ConvertError {
+    let location = format!(" at line {} column {}", source.line(), source.column());
+    let message = serde_json::from_slice::<Value>(bytes)
+        .ok()
+        .and_then(|value| find_missing_type(&value))
+        .unwrap_or_else(|| source.to_string().trim_end_matches(&location).to_string());
+
+    ConvertError::Parse {
+        path: path.to_path_buf(),
+        line: source.line(),
+        column: source.column(),
+        message,
+        source,
+    }
+}
+
+/// Looks for a cell or output lacking its type tag. Unknown tag values are not an error,
+/// they deserialize into the `Unknown` variants.
+fn find_missing_type(notebook: &Value) -> Option<String> {
+    let cells = notebook.get("cells")?.as_array()?;
+    for (i, cell) in cells.iter().enumerate() {
+        if !cell.get("cell_type").is_some_and(|t| t.is_string()) {
+            return Some(format!("cell {} has no `cell_type`", i));
+        }
+
+        let outputs = cell.get("outputs").and_then(|o| o.as_array());
+        for (j, output) in outputs.into_iter().flatten().enumerate() {
+            if !output.get("output_type").is_some_and(|t| t.is_string()) {
+                return Some(format!("output {} of cell {} has no `output_type`", j, i));
+            }
+        }
+    }
+
+    None
+}
+
+fn estimate_cell_len(cell: &Cell) -> usize {
+    match cell {
+        Cell::Markdown { source, .. } => source.len() + 4,
+        Cell::Raw { source, .. } => source.len() + 4,
+        Cell::Unknown => 0,
+        Cell::Code { source, outputs, .. } => {
+            let src_len = source.len() + 12; // fenced code block overhead
+            let outputs_len: usize = outputs.iter().map(|o| estimate_output_len(o)).sum();
+            src_len + outputs_len
+        }
+    }
+}
+
+fn estimate_output_len(output: &Output) -> usize {
+    match output {
+        Output::Stream { text, .. } => text.len() + 8,
+        Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
+            // Pick the first textual value we might include (handle arrays/objects)
+            if let Some(s) = data.get("text/markdown").and_then(value_to_text) {
+                s.len() + 4
+            } else if let Some(s) = data.get("text/plain").and_then(value_to_text) {
+                s.len() + 8
+            } else if let Some(s) = data.get("image/png").and_then(value_to_text) {
+                s.len() + 32
+            } else {
+                16
+            }
+        }
+        Output::Error { traceback, .. } => traceback.len() + 16,
+        Output::Unknown => 0,
+    }
+}
+
+/// Prepares SVG for inlining into HTML: drops the XML prolog and blank lines
+#[cfg(feature = "images")]
+fn inline_svg_markup(svg: &str) -> String {
+    let start = svg.find("<svg").unwrap_or(0);
+    html_block(&svg[start..])
+}
+
+/// Drops blank lines from raw HTML, since a blank line would end the HTML block and let markdown
+/// parsing resume in the middle of it (e.g. indented CSS in pandas' `<style scoped>` becoming a
+/// code block)
+fn html_block(html: &str) -> String {
+    html.lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// JavaScript charting libraries whose figure specs can be rendered in the page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InteractiveKind {
+    Plotly,
+    Vega,
+}
+
+/// Finds a Plotly or Vega/Vega-Lite figure spec in an output's mime bundle
+fn find_interactive_spec(data: &Map<String, Value>) -> Option<(InteractiveKind, &Value)> {
+    if let Some(spec) = data.get("application/vnd.plotly.v1+json") {
+        return Some((InteractiveKind::Plotly, spec));
+    }
+    // Chosen by name rather than map order, so the result doesn't depend on how serde_json stores
+    // the bundle; the greatest name prefers Vega-Lite over Vega and newer versions over older
+    data.iter()
+        .filter(|(mime, _)| {
+            (mime.starts_with("application/vnd.vegalite.v") || mime.starts_with("application/vnd.vega.v"))
+                && mime.ends_with("+json")
+        })
+        .max_by_key(|(mime, _)| mime.as_str())
+        .map(|(_, spec)| (InteractiveKind::Vega, spec))
+}
+
+/// Emits a container plus the scripts that load the charting library from its CDN and render the spec
+fn push_interactive(md: &mut String, kind: InteractiveKind, spec: &Value, position: (usize, usize)) {
+    let spec_json = serde_json::to_string(spec)
+        .unwrap_or_else(|_| "{}".to_string())
+        // keep a `</script>` inside string values from closing the script element
+        .replace("</", "<\\/");
+
+    // Deterministic across builds; the output position keeps identical figures apart, and the
+    // spec hash keeps notebooks included into the same page apart
+    let mut hasher = DefaultHasher::new();
+    spec_json.hash(&mut hasher);
+    let id = format!("jupyter-plot-{}-{}-{:08x}", position.0, position.1, hasher.finish() as u32);
+
+    md.push_str(&format!("<div id=\"{}\"></div>\n", id));
+    match kind {
+        InteractiveKind::Plotly => {
+            md.push_str("<script src=\"https://cdn.plot.ly/plotly-2.35.2.min.js\"></script>\n");
+            md.push_str(&format!(
+                "<script>\n(function() {{ var fig = {}; Plotly.newPlot(\"{}\", fig.data, fig.layout || {{}}, fig.config || {{}}); }})();\n</script>\n\n",
+                spec_json, id
+            ));
+        }
+        InteractiveKind::Vega => {
+            md.push_str("<script src=\"https://cdn.jsdelivr.net/npm/vega@5\"></script>\n");
+            md.push_str("<script src=\"https://cdn.jsdelivr.net/npm/vega-lite@5\"></script>\n");
+            md.push_str("<script src=\"https://cdn.jsdelivr.net/npm/vega-embed@6\"></script>\n");
+            md.push_str(&format!("<script>\nvegaEmbed(\"#{}\", {});\n</script>\n\n", id, spec_json));
+        }
+    }
+}
+
+/// Pretty-prints an `application/json` value, which may be a JSON value or a string containing JSON
+fn json_to_pretty(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => match serde_json::from_str::<Value>(s) {
+            Ok(parsed) => serde_json::to_string_pretty(&parsed).ok(),
+            Err(_) => Some(s.clone()),
+        },
+        other => serde_json::to_string_pretty(other).ok(),
+    }
+}
+
+/// Text of a mime bundle value. nbformat stores text as a string or an array of line strings;
+/// any other JSON (numbers, or arrays of them, from unusual kernels) is shown as JSON.
+fn value_to_text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(arr) if arr.iter().all(Value::is_string) => {
+            Some(arr.iter().filter_map(Value::as_str).collect())
+        }
+        Value::Array(arr) => serde_json::to_string(arr).ok(),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Object(o) => serde_json::to_string(o).ok(),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Null => None,
+    }
+}
+
+/// Markdown cell tags that become mdbook-admonish blocks when `admonition_from_tags` is set
+const ADMONITION_TYPES: [&str; 12] = [
+    "note", "abstract", "info", "tip", "success", "question",
+    "warning", "failure", "danger", "bug", "example", "quote",
+];
+
+/// Appends the markdown for `cell`. Returns false if the cell was dropped by its tags or as empty.
+fn process_cell(md: &mut String, cell: Cell, index: usize, number: Option<usize>, language: &str, assets: &mut AssetWriter, options: &ConvertOptions) -> Result<bool> {
+    let language = options
+        .language_aliases
+        .get(language)
+        .map(String::as_str)
+        .unwrap_or(language);
+    let language = match &options.unknown_language_fallback {
+        Some(fallback) if !HIGHLIGHT_LANGUAGES.contains(&language.to_lowercase().as_str()) => fallback.as_str(),
+        _ => language,
+    };
+
+    let tags = cell.tags();
+    if tags.contains(&"remove-cell") {
+        return Ok(false);
+    }
+    if !options.include_tags.is_empty() && !tags.iter().any(|tag| options.include_tags.iter().any(|t| t == tag)) {
+        return Ok(false);
+    }
+    if options.cell_markers {
+        md.push_str(&format!("<!-- cell {} ({}) -->\n\n", index, cell.kind()));
+    }
+    if options.emit_slide_markers {
+        // "-" means the cell simply continues the current slide
+        if let Some(slide_type) = cell.slide_type().filter(|t| *t != "-") {
+            // escaped so a stray `-->` in the metadata can't end the comment early
+            md.push_str(&format!("<!-- slide: {} -->\n\n", escape_html(slide_type)));
+        }
+    }
+    if options.cell_anchors && options.renderer == Renderer::Html {
+        // Index-based so links like `#cell-7` keep working across rebuilds
+        md.push_str(&format!("<a id=\"cell-{}\"></a>\n\n", index));
+    }
+    let remove_input = tags.contains(&"remove-input");
+    let remove_output = tags.contains(&"remove-output");
+    // collapsing needs <details>, which only the HTML renderer reliably supports
+    let collapsible = options.renderer == Renderer::Html;
+    // Escaped where it is used, since markdown and HTML images need different escaping
+    let alt = cell
+        .image_alt()
+        .map(str::to_string)
+        .unwrap_or_else(|| options.default_alt.replace("{cell}", &index.to_string()));
+    let collapsed = |key| options.respect_jupyter_collapse && cell.jupyter_hidden(key);
+    let hide_input = collapsible && (tags.contains(&"hide-input") || collapsed("source_hidden"));
+    let hide_output = collapsible && (tags.contains(&"hide-output") || collapsed("outputs_hidden"));
+    // Outputs the notebook shows in a scroll box (`metadata.scrolled`); `"auto"` only scrolls long ones
+    let scrolled = collapsible
+        && options.respect_jupyter_collapse
+        && match cell.metadata().and_then(|m| m.get("scrolled")) {
+            Some(Value::Bool(scrolled)) => *scrolled,
+            Some(Value::String(auto)) if auto == "auto" => match &cell {
+                Cell::Code { outputs, .. } => outputs.iter().map(output_line_count).sum::<usize>() > AUTO_SCROLL_LINES,
+                _ => false,
+            },
+            _ => false,
+        };
+    let admonition = if options.admonition_from_tags {
+        ADMONITION_TYPES.iter().copied().find(|kind| tags.contains(kind))
+    } else {
+        None
+    };
+
+    match cell {
+        Cell::Markdown { source, attachments, .. } => {
+            let mut text = source.into_source();
+            #[cfg(not(feature = "images"))]
+            for name in attachments.iter().flat_map(Map::keys) {
+                if text.contains(&format!("attachment:{}", name)) {
+                    md.push_str(&format!(
+                        "<!-- mdbook-jupyter: skipped attachment {}, built without the `images` feature -->\n\n",
+                        escape_html(name)
+                    ));
+                }
+            }
+            #[cfg(feature = "images")]
+            if let Some(attachments) = attachments {
+                text = resolve_attachments(text, &attachments, assets, options)?;
+            }
+            if options.normalize_math {
+                text = normalize_math_delimiters(&text);
+            }
+            if let Some(kind) = admonition {
+                // a longer fence keeps code blocks inside the cell from closing the admonition
+                let fence = fence_for(&text);
+                md.push_str(&format!("{}admonish {}\n{}\n{}\n\n", fence, kind, text, fence));
+            } else {
+                // A source already ending in newlines would otherwise leave extra blank lines
+                md.push_str(text.trim_end_matches('\n'));
+                md.push_str("\n\n");
+            }
+        }
+        Cell::Code { source, outputs, execution_count, .. } => {
+            let source = source.into_source();
+            // Leftover blank cells from editing would otherwise render as empty code blocks
+            if !options.keep_empty_cells && source.trim().is_empty() && outputs.is_empty() {
+                return Ok(false);
+            }
+
+            // The blank line after the opening div lets nested fences render as markdown
+            let emit_cell = |md: &mut String| -> Result<()> {
+                if let Some(number) = number {
+                    md.push_str(&format!("<span class=\"cell-num\">[{}]</span>\n\n", number));
+                }
+                let emit_input = |md: &mut String| -> Result<()> {
+                    if remove_input {
+                        return Ok(());
+                    }
+                    if options.show_prompts {
+                        push_prompt(md, "jupyter-prompt-in", "In", execution_count);
+                    }
+                    if hide_input {
+                        open_details(md, "Show code");
+                    }
+                    let fence = if options.fence_guard { fence_for(&source) } else { "```".to_string() };
+                    if options.wrap_input && options.renderer == Renderer::Html {
+                        wrap_in_div(md, "jupyter-input", |md| {
+                            push_fence(md, &fence, language, &source);
+                            Ok(())
+                        })?;
+                    } else {
+                        push_fence(md, &fence, language, &source);
+                    }
+                    if hide_input {
+                        close_details(md);
+                    }
+                    Ok(())
+                };
+
+                // The `In` prompt only depends on the execution count, so an executed cell without
+                // outputs still shows it; outputs that all render empty leave no block behind
+                let emit_outputs = |md: &mut String| -> Result<()> {
+                    if remove_output || outputs.is_empty() {
+                        return Ok(());
+                    }
+                    let start = md.len();
+                    if hide_output {
+                        open_details(md, "Show output");
+                    }
+                    let body_start = md.len();
+                    let emit_all = |md: &mut String| -> Result<()> {
+                        for (output_index, output) in merge_streams(outputs).into_iter().enumerate() {
+                            process_output(md, output, (index, output_index), &alt, assets, options).map_err(|e| {
+                                ConvertError::Output {
+                                    index: output_index,
+                                    source: Box::new(e),
+                                }
+                            })?;
+                        }
+                        Ok(())
+                    };
+                    if scrolled {
+                        let style = format!("max-height:{};overflow:auto", options.scroll_max_height);
+                        wrap_in_div_with(md, &format!("style=\"{}\"", escape_html(&style)), emit_all)?;
+                    } else {
+                        emit_all(md)?;
+                    }
+                    if md.len() == body_start {
+                        md.truncate(start);
+                    } else if hide_output {
+                        close_details(md);
+                    }
+                    Ok(())
+                };
+
+                match options.output_position {
+                    OutputPosition::After => {
+                        emit_input(md)?;
+                        emit_outputs(md)
+                    }
+                    OutputPosition::Before => {
+                        emit_outputs(md)?;
+                        emit_input(md)
+                    }
+                }
+            };
+
+            if options.group_cell && options.renderer == Renderer::Html {
+                wrap_in_div(md, "jupyter-cell", emit_cell)?;
+            } else {
+                emit_cell(md)?;
+            }
+        }
+        Cell::Raw { source, metadata } => {
+            // nbformat stores the target format in `format`; older notebooks use `raw_mimetype`
+            let format = metadata
+                .as_ref()
+                .and_then(|m| m.get("format").or_else(|| m.get("raw_mimetype")))
+                .and_then(|f| f.as_str());
+
+            match format {
+                Some("text/html") if options.renderer != Renderer::Html => {
+                    push_code_fence(md, "html", &source.into_source());
+                }
+                Some("text/latex") => {
+                    md.push_str("$$\n");
+                    md.push_str(strip_math_delimiters(&source.into_source()));
+                    md.push_str("\n$$\n\n");
+                }
+                // HTML passes through mdbook as-is; other formats keep the verbatim behavior
+                _ => {
+                    md.push_str(&source.into_source());
+                    md.push_str("\n\n");
+                }
+            }
+        }
+        Cell::Unknown => {
+            md.push_str("<!-- mdbook-jupyter: skipped a cell of unsupported type -->\n\n");
+        }
+    }
+
+    Ok(true)
+}
+
+/// Coalesces consecutive stream outputs with the same name (e.g. `stdout`) into one,
+/// preserving order so that `stdout` and `stderr` still end up in separate blocks
+fn merge_streams(outputs: Vec<Output>) -> Vec<Output> {
+    let mut merged: Vec<Output> = Vec::with_capacity(outputs.len());
+
+    for output in outputs.into_iter() {
+        match output {
+            Output::Stream { name, text } => {
+                if let Some(Output::Stream { name: prev_name, text: prev_text }) = merged.last_mut() {
+                    if *prev_name == name {
+                        // Kept raw so a `\r` redraw can overwrite text from the previous chunk
+                        let mut joined = std::mem::replace(prev_text, MultilineString::Single(String::new())).join();
+                        joined.push_str(&text.join());
+                        *prev_text = MultilineString::Single(joined);
+                        continue;
+                    }
+                }
+                merged.push(Output::Stream { name, text });
+            }
+            other => merged.push(other),
+        }
+    }
+
+    merged
+}
+
+/// Opens a collapsible block; the blank line lets mdbook keep parsing markdown inside it
+fn open_details(md: &mut String, summary: &str) {
+    md.push_str("<details>\n<summary>");
+    md.push_str(&escape_html(summary));
+    md.push_str("</summary>\n\n");
+}
+
+fn close_details(md: &mut String) {
+    md.push_str("</details>\n\n");
+}
+
+/// Emits an `In [n]:` / `Out [n]:` prompt; a missing execution count renders as `[ ]`
+fn push_prompt(md: &mut String, class: &str, label: &str, execution_count: Option<u32>) {
+    let count = execution_count.map_or_else(|| " ".to_string(), |n| n.to_string());
+    md.push_str(&format!(
+        "<span class=\"{}\">{} [{}]:</span>\n\n",
+        escape_html(class),
+        escape_html(label),
+        count
+    ));
+}
+
+fn push_code_fence(md: &mut String, language: &str, source: &str) {
+    push_fence(md, "```", language, source);
+}
+
+fn push_fence(md: &mut String, fence: &str, language: &str, source: &str) {
+    md.push_str(fence);
+    md.push_str(language);
+    md.push('\n');
+    md.push_str(source);
+    md.push('\n');
+    md.push_str(fence);
+    md.push_str("\n\n");
+}
+
+/// A backtick fence longer than any backtick run in `source`, so fences inside it can't close it
+fn fence_for(source: &str) -> String {
+    let longest = source
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Info string for plain-text output fences; empty (a bare fence) unless configured.
+/// With `output_no_copy` a `no-copy` attribute is added, which mdbook turns into a class on the
+/// `<code>` element so themes can hide the copy button.
+fn output_fence_lang(options: &ConvertOptions) -> String {
+    let lang = options.output_fence_lang.as_deref().unwrap_or("");
+    if options.output_no_copy {
+        // mdbook reads the first attribute as the language, so don't let `no-copy` take that slot
+        format!("{},no-copy", if lang.is_empty() { "text" } else { lang })
+    } else {
+        lang.to_string()
+    }
+}
+
+/// Runs `emit` inside `<div class="...">`; the wrapper is dropped again if nothing was emitted
+fn wrap_in_div<F>(md: &mut String, class: &str, emit: F) -> Result<()>
+where
+    F: FnOnce(&mut String) -> Result<()>,
+{
+    wrap_in_div_with(md, &format!("class=\"{}\"", escape_html(class)), emit)
+}
+
+/// Like `wrap_in_div`, with the div's already escaped `attributes` given verbatim
+fn wrap_in_div_with<F>(md: &mut String, attributes: &str, emit: F) -> Result<()>
+where
+    F: FnOnce(&mut String) -> Result<()>,
+{
+    let start = md.len();
+    md.push_str("<div ");
+    md.push_str(attributes);
+    md.push_str(">\n\n");
+    let body_start = md.len();
+
+    emit(md)?;
+
+    if md.len() == body_start {
+        md.truncate(start);
+    } else {
+        md.push_str("</div>\n\n");
+    }
+    Ok(())
+}
+
+/// `alt` is the alt text for any image the output produces
+/// `position` is the (cell, output) index pair used for `data_attributes`.
+fn process_output(
+    md: &mut String,
+    output: Output,
+    position: (usize, usize),
+    alt: &str,
+    assets: &mut AssetWriter,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let line_count = output_line_count(&output);
+    let collapse = options.renderer == Renderer::Html
+        && options.collapse_output_over_lines.is_some_and(|max| line_count > max);
+
+    if collapse {
+        open_details(md, &format!("Output ({} lines)", line_count));
+    }
+
+    let html = options.renderer == Renderer::Html;
+    let mut attributes = Vec::new();
+    if options.wrap_output && html {
+        attributes.push("class=\"jupyter-output\"".to_string());
+    }
+    if options.data_attributes && html {
+        attributes.push(format!("data-cell=\"{}\" data-output=\"{}\"", position.0, position.1));
+    }
+
+    if attributes.is_empty() {
+        emit_output(md, output, position, alt, assets, options)?;
+    } else {
+        wrap_in_div_with(md, &attributes.join(" "), |md| emit_output(md, output, position, alt, assets, options))?;
+    }
+
+    if collapse {
+        close_details(md);
+    }
     Ok(())
 }
-    
+
+/// Output length beyond which `scrolled: "auto"` puts outputs in a scroll box, as the classic notebook does
+const AUTO_SCROLL_LINES: usize = 100;
+
+/// Number of text lines an output shows; for rich outputs the longest textual representation counts
+fn output_line_count(output: &Output) -> usize {
+    match output {
+        Output::Stream { text, .. } => text.line_count(),
+        Output::DisplayData { data, .. } | Output::ExecuteResult { data, .. } => {
+            ["text/plain", "text/html", "text/markdown", "application/json"]
+                .iter()
+                .filter_map(|mime| data.get(*mime).and_then(value_to_text))
+                .map(|text| text.lines().count())
+                .max()
+                .unwrap_or(0)
+        }
+        Output::Error { traceback, .. } => traceback.line_count() + 1,
+        Output::Unknown => 0,
+    }
+}
+
+/// Languages (and aliases) the highlight.js build bundled with mdbook recognizes
+const HIGHLIGHT_LANGUAGES: &[&str] = &[
+    "apache", "armasm", "bash", "c", "c++", "cc", "coffeescript", "console", "cpp", "cs", "csharp", "css", "d",
+    "diff", "go", "golang", "h", "handlebars", "haskell", "hpp", "hs", "html", "http", "ini", "java",
+    "javascript", "jl", "js", "json", "julia", "kotlin", "kt", "less", "lua", "makefile", "markdown", "md",
+    "mk", "nginx", "nim", "nix", "objc", "objectivec", "perl", "php", "pl", "plaintext", "properties", "py",
+    "python", "r", "rb", "rs", "ruby", "rust", "scala", "scss", "sh", "shell", "sql", "swift", "text", "toml",
+    "ts", "txt", "typescript", "x86asm", "xml", "yaml", "yml", "zsh",
+];
+
+/// Mime types `emit_mime` knows how to render
+const SUPPORTED_MIMES: [&str; 12] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/svg+xml",
+    "application/pdf",
+    "text/markdown",
+    "text/latex",
+    "application/json",
+    "text/html",
+    "application/javascript",
+    "text/plain",
+];
+
+/// ipywidgets view model; it needs a live kernel, so it's never rendered
+const WIDGET_MIME: &str = "application/vnd.jupyter.widget-view+json";
+
+/// Lists the representations present in a mime bundle, best first, following `priority`.
+/// `text/html` only competes when it is emitted as raw HTML; otherwise it is the last resort.
+/// Widget views are always passed over for the image or text snapshot stored beside them.
+fn ranked_mimes<'a>(
+    data: &'a Map<String, Value>,
+    priority: &'a [String],
+    options: &ConvertOptions,
+) -> Vec<(&'a str, &'a Value)> {
+    let raw_html = options.render_html_output && options.renderer == Renderer::Html;
+
+    let mut ranked: Vec<_> = priority
+        .iter()
+        .map(String::as_str)
+        .filter(|mime| *mime != WIDGET_MIME && SUPPORTED_MIMES.contains(mime))
+        .filter(|mime| raw_html || *mime != "text/html")
+        .filter_map(|mime| data.get(mime).filter(|v| !v.is_null()).map(|v| (mime, v)))
+        .collect();
+
+    if ranked.is_empty() {
+        if let Some(html) = data.get("text/html") {
+            ranked.push(("text/html", html));
+        }
+    }
+    ranked
+}
+
+/// Width and height for `mime` from output metadata like `{"image/png": {"width": 640}}`
+#[cfg(feature = "images")]
+fn image_size(metadata: Option<&Value>, mime: &str) -> (Option<u64>, Option<u64>) {
+    let Some(dims) = metadata.and_then(|m| m.get(mime)) else {
+        return (None, None);
+    };
+    let dimension = |key| dims.get(key).and_then(Value::as_f64).map(|v| v.round() as u64);
+    (dimension("width"), dimension("height"))
+}
+
+/// Emits an image. With a known size it becomes an `<img>` so large figures are scaled down,
+/// otherwise markdown image syntax is used.
+#[cfg(feature = "images")]
+fn push_image(md: &mut String, src: &str, alt: &str, size: (Option<u64>, Option<u64>)) {
+    if size == (None, None) {
+        md.push_str(&format!("![{}]({})\n\n", markdown_alt(alt), src));
+        return;
+    }
+
+    md.push_str(&format!("<img src=\"{}\" alt=\"{}\"", escape_html(src), escape_html(alt)));
+    if let Some(width) = size.0 {
+        md.push_str(&format!(" width=\"{}\"", width));
+    }
+    if let Some(height) = size.1 {
+        md.push_str(&format!(" height=\"{}\"", height));
+    }
+    md.push_str(">\n\n");
+}
+
+/// Renders one representation of a display_data/execute_result output.
+/// Values may be strings or arrays of strings.
+/// `metadata` is the output's metadata, which may give the size of images by mime type.
+fn emit_mime(
+    md: &mut String,
+    mime: &str,
+    value: &Value,
+    metadata: Option<&Value>,
+    alt: &str,
+    assets: &mut AssetWriter,
+    options: &ConvertOptions,
+) -> Result<()> {
+    #[cfg(feature = "images")]
+    let size = if options.use_img_dimensions && options.renderer == Renderer::Html {
+        image_size(metadata, mime)
+    } else {
+        (None, None)
+    };
+    // Only images need these
+    #[cfg(not(feature = "images"))]
+    let _ = (metadata, alt, &assets);
+
+    match mime {
+        #[cfg(feature = "images")]
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" => {
+            let Some(img_b64) = value_to_text(value).map(|text| compact_base64(&text)) else {
+                return Ok(());
+            };
+            let target = image_target(mime, options);
+            let processed = process_image(&img_b64, mime, target, options)?;
+            let mime = match (&processed, target) {
+                (Some(_), Some(target)) => target,
+                _ => mime,
+            };
+            // Dimensions from the metadata describe the original image
+            let size = if processed.is_some() { (None, None) } else { size };
+            if options.embed_images {
+                // Embed image as base64 data URL
+                let img_b64 = processed.map_or(img_b64, |bytes| STANDARD.encode(bytes));
+                push_image(md, &format!("data:{};base64,{}", mime, img_b64), alt, size);
+            } else {
+                // decode and write to file
+                let extension = match mime {
+                    "image/jpeg" => "jpg",
+                    other => other.trim_start_matches("image/"),
+                };
+                let decoded = match processed {
+                    Some(bytes) => bytes,
+                    None => decode_base64(&img_b64, mime)?,
+                };
+                let link = assets.write(extension, &decoded)?;
+                push_image(md, &link, alt, size);
+            }
+        }
+        #[cfg(feature = "images")]
+        "image/svg+xml" => {
+            let Some(svg) = value_to_text(value) else {
+                return Ok(());
+            };
+            if options.inline_svg && options.renderer == Renderer::Html {
+                // Inline markup stays crisp and can be styled with CSS
+                md.push_str(&inline_svg_markup(&svg));
+                md.push_str("\n\n");
+            } else if options.embed_images {
+                // Embed SVG as base64 data URL
+                let svg_b64 = STANDARD.encode(&svg);
+                push_image(md, &format!("data:image/svg+xml;base64,{}", svg_b64), alt, size);
+            } else {
+                let link = assets.write("svg", svg.as_bytes())?;
+                push_image(md, &link, alt, size);
+            }
+        }
+        #[cfg(feature = "images")]
+        "application/pdf" => {
+            // Browsers don't reliably show data-URL PDFs inline, so the file is written even
+            // when images are embedded
+            let Some(pdf_b64) = value_to_text(value) else {
+                return Ok(());
+            };
+            let decoded = decode_base64(&pdf_b64, mime)?;
+            let link = assets.write("pdf", &decoded)?;
+            md.push_str(&format!("[output PDF]({})\n\n", link));
+        }
+        #[cfg(not(feature = "images"))]
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" | "image/svg+xml" | "application/pdf" => {
+            md.push_str(&format!(
+                "<!-- mdbook-jupyter: skipped {} output, built without the `images` feature -->\n\n",
+                mime
+            ));
+        }
+        "text/markdown" => {
+            if let Some(mdtext) = value_to_text(value) {
+                if options.render_markdown_output {
+                    md.push_str(&mdtext);
+                    md.push_str("\n\n");
+                } else {
+                    push_code_fence(md, "markdown", &mdtext);
+                }
+            }
+        }
+        "text/latex" => {
+            if let Some(latex) = value_to_text(value) {
+                md.push_str("$$\n");
+                md.push_str(strip_math_delimiters(&latex));
+                md.push_str("\n$$\n\n");
+            }
+        }
+        "application/json" => {
+            if let Some(json) = json_to_pretty(value) {
+                push_code_fence(md, "json", &json);
+            }
+        }
+        "text/html" => {
+            let Some(html) = value_to_text(value) else {
+                return Ok(());
+            };
+            if options.render_html_output && options.renderer == Renderer::Html {
+                // Raw HTML passes through mdbook untouched, so rich reprs (e.g. DataFrames) render
+                md.push_str(&html_block(&html));
+                md.push_str("\n\n");
+            } else {
+                push_code_fence(md, "html", &html);
+            }
+        }
+        "application/javascript" => {
+            let Some(script) = value_to_text(value) else {
+                return Ok(());
+            };
+            if options.allow_scripts && options.renderer == Renderer::Html {
+                md.push_str("<script>\n");
+                md.push_str(&script);
+                md.push_str("\n</script>\n\n");
+            } else {
+                md.push_str("<!-- mdbook-jupyter: skipped a script output (enable allow_scripts to include it) -->\n\n");
+            }
+        }
+        "text/plain" => {
+            let Some(text) = value_to_text(value) else {
+                return Ok(());
+            };
+            if !options.keep_empty_cells && text.trim().is_empty() {
+                return Ok(());
+            }
+            let text = limit_lines(text, options.max_output_lines);
+            if push_ansi_html(md, None, &text, options) {
+                return Ok(());
+            }
+            let text = if options.strip_ansi { strip_ansi_codes(&text) } else { text };
+            push_code_fence(md, &output_fence_lang(options), &text);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// `position` is the (cell, output) index pair, which keeps interactive figure ids unique
+fn emit_output(
+    md: &mut String,
+    output: Output,
+    position: (usize, usize),
+    alt: &str,
+    assets: &mut AssetWriter,
+    options: &ConvertOptions,
+) -> Result<()> {
+    if options.show_prompts {
+        if let Output::ExecuteResult { execution_count, .. } = &output {
+            push_prompt(md, "jupyter-prompt-out", "Out", *execution_count);
+        }
+    }
+
+    let priority = match &output {
+        Output::ExecuteResult { .. } => &options.result_mime_priority,
+        _ => &options.mime_priority,
+    };
+
+    match output {
+        Output::Stream { name, text } => {
+            let text = limit_lines(terminal_text(text.join()), options.max_output_lines);
+            if !options.keep_empty_cells && text.trim().is_empty() {
+                return Ok(());
+            }
+            if push_ansi_html(md, (name == "stderr").then_some(options.stderr_class.as_str()), &text, options) {
+                return Ok(());
+            }
+            let text = if options.strip_ansi { strip_ansi_codes(&text) } else { text };
+            if name == "stderr" {
+                if options.renderer == Renderer::Html {
+                    md.push_str(&format!("<pre class=\"{}\">", escape_html(&options.stderr_class)));
+                    md.push_str(&escape_html(&text));
+                    md.push_str("</pre>\n\n");
+                } else {
+                    md.push_str("```");
+                    md.push_str(&options.stderr_class);
+                    md.push('\n');
+                    md.push_str(&text);
+                    md.push_str("\n```\n\n");
+                }
+            } else {
+                push_code_fence(md, &output_fence_lang(options), &text);
+            }
+        }
+        Output::DisplayData { data, metadata, .. } | Output::ExecuteResult { data, metadata, .. } => {
+            // Interactive figures win over their static fallbacks when enabled
+            let interactive = if options.render_interactive && options.renderer == Renderer::Html {
+                find_interactive_spec(&data)
+            } else {
+                None
+            };
+
+            if let Some((kind, spec)) = interactive {
+                push_interactive(md, kind, spec, position);
+            } else {
+                let ranked = ranked_mimes(&data, priority, options);
+                if ranked.is_empty() && data.contains_key(WIDGET_MIME) {
+                    md.push_str("<!-- mdbook-jupyter: skipped a widget output with no static snapshot -->\n\n");
+                }
+
+                let shown = if options.emit_all_mimes { ranked.len() } else { 1 };
+                for (mime, value) in ranked.into_iter().take(shown) {
+                    emit_mime(md, mime, value, metadata.as_ref(), alt, assets, options)?;
+                }
+            }
+        }
+        Output::Error { ename, evalue, traceback } => {
+            // traceback entries are separate lines without trailing newlines
+            let traceback = match traceback {
+                MultilineString::Single(s) => s,
+                MultilineString::Multi(v) => v.join("\n"),
+            };
+            let colored = options.ansi_to_html && options.renderer == Renderer::Html;
+            let traceback = if options.strip_ansi && !colored {
+                strip_ansi_codes(&traceback)
+            } else {
+                traceback
+            };
+            let traceback = limit_lines(traceback, options.max_output_lines);
+
+            if options.renderer == Renderer::Html {
+                wrap_in_div(md, &options.error_class, |md| {
+                    md.push_str(&format!(
+                        "<p><strong>{}</strong>: {}</p>\n\n",
+                        escape_html(&ename),
+                        escape_html(&evalue)
+                    ));
+                    md.push_str("<pre>");
+                    if colored {
+                        md.push_str(&ansi_to_html(&traceback));
+                    } else {
+                        md.push_str(&escape_html(&traceback));
+                    }
+                    md.push_str("</pre>\n\n");
+                    Ok(())
+                })?;
+            } else {
+                md.push_str("```error\n");
+                md.push_str(&ename);
+                md.push_str(": ");
+                md.push_str(&evalue);
+                md.push('\n');
+                md.push_str(&traceback);
+                md.push_str("\n```\n\n");
+            }
+        }
+        Output::Unknown => {
+            md.push_str("<!-- mdbook-jupyter: skipped an output of unsupported type -->\n\n");
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes asset files for one notebook, named by a short hash of their content so identical
+/// images are stored once, also when several notebooks show them, and rebuilds produce the
+/// same filenames
+#[cfg_attr(not(feature = "images"), allow(dead_code))]
+struct AssetWriter<'a> {
+    dir: &'a Path,
+    /// Link to the assets directory from the chapter's rendered page
+    link_base: String,
+    /// Only compute filenames and links, never touch the filesystem
+    dry_run: bool,
+    assets: Vec<AssetInfo>,
+}
+
+impl<'a> AssetWriter<'a> {
+    /// `link_dir` is where the assets appear relative to the rendered book root
+    fn new(dir: &'a Path, book_path: &Path, link_dir: &str, dry_run: bool) -> Self {
+        let mut link_base = root_relative_prefix(book_path);
+        if !link_dir.is_empty() {
+            link_base.push_str(link_dir);
+            link_base.push('/');
+        }
+
+        AssetWriter {
+            dir,
+            link_base,
+            dry_run,
+            assets: Vec::new(),
+        }
+    }
+
+    /// Writes the asset file unless an identical one exists and returns the link to use in markdown.
+    /// If a different file already holds the hashed name, a numbered suffix is appended instead.
+    #[cfg(feature = "images")]
+    fn write(&mut self, extension: &str, bytes: &[u8]) -> Result<String> {
+        let hash = fnv1a(bytes);
+        let mut collisions = 0;
+        let (filename, out_path) = loop {
+            let filename = match collisions {
+                0 => format!("img_{:08x}.{}", hash, extension),
+                n => format!("img_{:08x}_{}.{}", hash, n, extension),
+            };
+            let out_path = self.dir.join(&filename);
+            // A dry run records names without writing, so a recorded but missing file is taken as a match
+            match fs::read(&out_path) {
+                Ok(existing) if existing != bytes => collisions += 1,
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(ConvertError::AssetWrite { path: out_path, source: e });
+                }
+                _ => break (filename, out_path),
+            }
+        };
+
+        if !self.assets.iter().any(|asset| asset.path == out_path) {
+            let written = !out_path.exists();
+            if written && !self.dry_run {
+                // Not created up front when images are embedded, but some assets are always files
+                create_dir_all(self.dir).map_err(|source| ConvertError::AssetWrite {
+                    path: self.dir.to_path_buf(),
+                    source,
+                })?;
+                fs::write(&out_path, bytes).map_err(|source| ConvertError::AssetWrite {
+                    path: out_path.clone(),
+                    source,
+                })?;
+            }
+            self.assets.push(AssetInfo {
+                path: out_path,
+                size: bytes.len(),
+                written,
+            });
+        }
+
+        Ok(format!("{}{}", self.link_base, filename))
+    }
+}
+
+/// 32-bit FNV-1a hash. Unlike `DefaultHasher` its output is fixed by definition, so asset names
+/// stay the same across rebuilds and toolchain upgrades and `mdbook serve` doesn't pile up copies.
+#[cfg(feature = "images")]
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &b| (hash ^ u32::from(b)).wrapping_mul(0x0100_0193))
+}
+
+/// Where the assets appear relative to the rendered book root. A configured `assets_dir` starts
+/// with the renderer's output directory, which is the book root once rendered; otherwise the
+/// assets directory is assumed to sit directly in it.
+fn assets_link_dir(assets_out: &Path, options: &ConvertOptions) -> String {
+    match &options.assets_dir {
+        Some(dir) => Path::new(dir)
+            .components()
+            .skip(1)
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+        None => assets_out
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+/// Relative path from a chapter's rendered page to the book root, e.g. `../../` for `ch1/sub/page.ipynb`
+fn root_relative_prefix(book_path: &Path) -> String {
+    let depth = book_path
+        .parent()
+        .map_or(0, |dir| dir.components().filter(|c| matches!(c, Component::Normal(_))).count());
+    "../".repeat(depth)
+}
+
+/// True for a file named like one we generate: `img_<hash8>.ext`, with `_<n>` appended after a
+/// hash collision. Older names with a notebook prefix (`{prefix}img_<hash8>.ext`) or a sequential
+/// counter (`{prefix}output_NNN.ext`) are recognized too so they get cleaned up.
+fn is_generated_asset(filename: &str) -> bool {
+    let Some((stem, _extension)) = filename.rsplit_once('.') else {
+        return false;
+    };
+    let is_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+
+    if let Some(idx) = stem.rfind("img_") {
+        let rest = &stem[idx + "img_".len()..];
+        let (hash, suffix_ok) = match rest.split_once('_') {
+            Some((hash, n)) => (hash, is_digits(n)),
+            None => (rest, true),
+        };
+        if suffix_ok && hash.len() == 8 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return true;
+        }
+    }
+
+    stem.rfind("output_").is_some_and(|idx| is_digits(&stem[idx + "output_".len()..]))
+}
+
+/// Removes previously generated asset files whose file name satisfies `is_stale`.
+/// Files not following our naming pattern are left alone. Returns the number of files removed.
+pub fn remove_generated_assets<F>(assets_out: &Path, is_stale: F) -> Result<usize>
+where
+    F: Fn(&str) -> bool,
+{
+    let asset_error = |source: std::io::Error| ConvertError::AssetWrite {
+        path: assets_out.to_path_buf(),
+        source,
+    };
+
+    let entries = match fs::read_dir(assets_out) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(asset_error(e)),
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry.map_err(asset_error)?;
+        let filename = entry.file_name();
+        let Some(name) = filename.to_str().filter(|name| is_generated_asset(name)) else {
+            continue;
+        };
+        if is_stale(name) && entry.file_type().map_err(asset_error)?.is_file() {
+            let path = entry.path();
+            fs::remove_file(&path).map_err(|source| ConvertError::AssetWrite { path, source })?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Rewrites `attachment:NAME` references in markdown source to data URLs or extracted asset files
+#[cfg(feature = "images")]
+fn resolve_attachments(source: String, attachments: &Map<String, Value>, assets: &mut AssetWriter, options: &ConvertOptions) -> Result<String> {
+    let mut source = source;
+
+    for (name, bundle) in attachments.iter() {
+        let reference = format!("attachment:{}", name);
+        if !source.contains(&reference) {
+            continue;
+        }
+
+        // The bundle maps mime types to base64 data, e.g. { "image/png": "<base64>" }. The image
+        // is picked by `mime_priority`, then by name, never by the map's iteration order.
+        let Some((mime, data)) = bundle.as_object().and_then(|b| {
+            options
+                .mime_priority
+                .iter()
+                .filter(|mime| mime.starts_with("image/"))
+                .find_map(|mime| b.get_key_value(mime.as_str()))
+                .or_else(|| {
+                    b.iter()
+                        .filter(|(mime, _)| mime.starts_with("image/"))
+                        .min_by_key(|(mime, _)| mime.as_str())
+                })
+                .and_then(|(mime, v)| value_to_text(v).map(|d| (mime.clone(), compact_base64(&d))))
+        }) else {
+            continue;
+        };
+
+        let target = if options.embed_images {
+            format!("data:{};base64,{}", mime, data)
+        } else {
+            let extension = match mime.as_str() {
+                "image/jpeg" => "jpg",
+                "image/svg+xml" => "svg",
+                other => other.trim_start_matches("image/"),
+            };
+            let decoded = decode_base64(&data, &mime)?;
+            assets.write(extension, &decoded)?
+        };
+
+        source = source.replace(&reference, &target);
+    }
+
+    Ok(source)
+}
+
+/// Standard alphabet, but decoding accepts data with or without its `=` padding
+#[cfg(feature = "images")]
+const LENIENT_BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Drops the line breaks notebooks sometimes leave inside base64 data, and the
+/// `data:image/png;base64,` prefix some tools store, so the result is bare base64
+#[cfg(feature = "images")]
+fn compact_base64(data: &str) -> String {
+    let data = data.trim_start();
+    let data = match data.strip_prefix("data:").and_then(|uri| uri.split_once(";base64,")) {
+        Some((_, payload)) => payload,
+        None => data,
+    };
+    data.chars().filter(|c| !c.is_ascii_whitespace()).collect()
+}
+
+/// Mime type a raster output is re-encoded to when `convert_images_to` applies to it
+#[cfg(feature = "images")]
+fn image_target(mime: &str, options: &ConvertOptions) -> Option<&'static str> {
+    match options.convert_images_to.as_deref() {
+        Some("webp") if cfg!(feature = "webp") && matches!(mime, "image/png" | "image/jpeg") => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Decoded image after downscaling to `max_image_width` and re-encoding as `target`, or `None`
+/// when it is left untouched
+#[cfg(any(feature = "resize", feature = "webp"))]
+fn process_image(
+    img_b64: &str,
+    mime: &str,
+    target: Option<&str>,
+    options: &ConvertOptions,
+) -> Result<Option<Vec<u8>>> {
+    // Only the first frame of a GIF would survive re-encoding
+    let max_width = options
+        .max_image_width
+        .filter(|_| cfg!(feature = "resize") && mime != "image/gif");
+    if max_width.is_none() && target.is_none() {
+        return Ok(None);
+    }
+    let decoded = decode_base64(img_b64, mime)?;
+    match crate::raster::process(&decoded, mime, max_width, target) {
+        Ok(processed) => Ok(processed),
+        Err(e) => {
+            warn!("Could not process {} output, keeping it as is: {}", mime, e);
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(all(feature = "images", not(any(feature = "resize", feature = "webp"))))]
+fn process_image(
+    _img_b64: &str,
+    _mime: &str,
+    _target: Option<&str>,
+    _options: &ConvertOptions,
+) -> Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Decodes the base64 `data` of a `mime` representation, tolerating missing padding
+#[cfg(feature = "images")]
+fn decode_base64(data: &str, mime: &str) -> Result<Vec<u8>> {
+    LENIENT_BASE64
+        .decode(compact_base64(data))
+        .map_err(|source| ConvertError::Base64Decode {
+            mime: mime.to_string(),
+            source,
+        })
+}
+
+/// Number of trailing lines kept when an output is truncated by `max_output_lines`
+const TRUNCATED_TAIL_LINES: usize = 3;
+
+/// Keeps the first `max_lines` lines and the last few lines of `text`, replacing the middle with a marker
+fn limit_lines(text: String, max_lines: Option<usize>) -> String {
+    let Some(max_lines) = max_lines else {
+        return text;
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    if lines.len() <= max_lines + TRUNCATED_TAIL_LINES {
+        return text;
+    }
+
+    let tail_start = lines.len() - TRUNCATED_TAIL_LINES;
+    let mut out = lines[..max_lines].join("\n");
+    out.push_str(&format!("\n... ({} lines omitted) ...\n", tail_start - max_lines));
+    out.push_str(&lines[tail_start..].join("\n"));
+    out
+}
+
+/// Strips `$$ ... $$` or `$ ... $` delimiters already present around LaTeX output
+fn strip_math_delimiters(latex: &str) -> &str {
+    let trimmed = latex.trim();
+    for delim in ["$$", "$"] {
+        if let Some(inner) = trimmed
+            .strip_prefix(delim)
+            .and_then(|rest| rest.strip_suffix(delim))
+        {
+            return inner.trim();
+        }
+    }
+    trimmed
+}
+
+/// Rewrites `\(..\)` to `$..$` and `\[..\]` to `$$..$$`, leaving fenced blocks and code spans alone
+fn normalize_math_delimiters(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut fence: Option<&str> = None;
+
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if let Some(open) = fence {
+            if trimmed.starts_with(open) {
+                fence = None;
+            }
+            out.push_str(line);
+            continue;
+        }
+        if let Some(marker) = ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+            fence = Some(marker);
+            out.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some(i) = rest.find(['`', '\\']) {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if rest.starts_with('`') {
+                // A code span runs to the next backtick run of the same length; copy it untouched
+                let run = rest.len() - rest.trim_start_matches('`').len();
+                let ticks = &rest[..run];
+                let end = rest[run..].find(ticks).map_or(run, |j| run + j + run);
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+                continue;
+            }
+
+            let next = rest[1..].chars().next();
+            let delimiter = match next {
+                Some('(') | Some(')') => Some("$"),
+                Some('[') | Some(']') => Some("$$"),
+                _ => None,
+            };
+            match delimiter {
+                Some(delimiter) => {
+                    out.push_str(delimiter);
+                    rest = &rest[2..];
+                }
+                None => {
+                    // Copy the escape with the character it escapes, so `\\(` stays literal
+                    let len = 1 + next.map_or(0, char::len_utf8);
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+            }
+        }
+        out.push_str(rest);
+    }
+
+    out
+}
+
+/// Removes ANSI CSI escape sequences (e.g. `\u{1b}[0;31m`) from text
+fn strip_ansi_codes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' && chars.peek() == Some(&'[') {
+            chars.next();
+            // parameter and intermediate bytes run until a final byte in '@'..='~'
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+/// With `ansi_to_html` and the HTML renderer, writes text containing ANSI escapes as a `<pre>`
+/// (of `class`, if given) with colored spans. Returns false when the text should be fenced instead.
+fn push_ansi_html(md: &mut String, class: Option<&str>, text: &str, options: &ConvertOptions) -> bool {
+    if !options.ansi_to_html || options.renderer != Renderer::Html || !text.contains('\u{1b}') {
+        return false;
+    }
+    match class {
+        Some(class) => md.push_str(&format!("<pre class=\"{}\">", escape_html(class))),
+        None => md.push_str("<pre>"),
+    }
+    md.push_str(&ansi_to_html(text));
+    md.push_str("</pre>\n\n");
+    true
+}
+
+/// The 16-color ANSI palette (xterm defaults), normal then bright
+const ANSI_PALETTE: [&str; 16] = [
+    "#000000", "#cd0000", "#00cd00", "#cdcd00", "#0000ee", "#cd00cd", "#00cdcd", "#e5e5e5",
+    "#7f7f7f", "#ff0000", "#00ff00", "#ffff00", "#5c5cff", "#ff00ff", "#00ffff", "#ffffff",
+];
+
+/// Text attributes set by ANSI SGR sequences
+#[derive(Debug, Default, Clone, PartialEq)]
+struct AnsiStyle {
+    foreground: Option<String>,
+    background: Option<String>,
+    bold: bool,
+}
+
+impl AnsiStyle {
+    /// Applies the `;`-separated codes of one SGR sequence; an empty list resets like `0`
+    fn apply(&mut self, params: &str) {
+        let mut codes = params.split(';').map(|p| p.parse::<usize>().unwrap_or(0));
+        while let Some(code) = codes.next() {
+            match code {
+                0 => *self = AnsiStyle::default(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.foreground = Some(ANSI_PALETTE[code - 30].to_string()),
+                90..=97 => self.foreground = Some(ANSI_PALETTE[code - 90 + 8].to_string()),
+                39 => self.foreground = None,
+                40..=47 => self.background = Some(ANSI_PALETTE[code - 40].to_string()),
+                100..=107 => self.background = Some(ANSI_PALETTE[code - 100 + 8].to_string()),
+                49 => self.background = None,
+                38 => self.foreground = extended_color(&mut codes),
+                48 => self.background = extended_color(&mut codes),
+                _ => {}
+            }
+        }
+    }
+
+    /// Inline CSS for the style, `None` for plain text
+    fn css(&self) -> Option<String> {
+        let mut css = Vec::new();
+        if let Some(color) = &self.foreground {
+            css.push(format!("color:{}", color));
+        }
+        if let Some(color) = &self.background {
+            css.push(format!("background-color:{}", color));
+        }
+        if self.bold {
+            css.push("font-weight:bold".to_string());
+        }
+        (!css.is_empty()).then(|| css.join(";"))
+    }
+}
+
+/// Reads the rest of a `38`/`48` sequence: `5;N` picks from the 256-color table, `2;R;G;B` is
+/// a 24-bit color
+fn extended_color(codes: &mut impl Iterator<Item = usize>) -> Option<String> {
+    match codes.next()? {
+        5 => {
+            let n = codes.next()?;
+            match n {
+                0..=15 => Some(ANSI_PALETTE[n].to_string()),
+                // 6x6x6 color cube
+                16..=231 => {
+                    let level = |v: usize| if v == 0 { 0 } else { 55 + v * 40 };
+                    let n = n - 16;
+                    Some(format!("#{:02x}{:02x}{:02x}", level(n / 36), level(n / 6 % 6), level(n % 6)))
+                }
+                // grayscale ramp
+                232..=255 => {
+                    let v = 8 + (n - 232) * 10;
+                    Some(format!("#{:02x}{:02x}{:02x}", v, v, v))
+                }
+                _ => None,
+            }
+        }
+        2 => {
+            let (r, g, b) = (codes.next()?, codes.next()?, codes.next()?);
+            Some(format!("#{:02x}{:02x}{:02x}", r.min(255), g.min(255), b.min(255)))
+        }
+        _ => None,
+    }
+}
+
+/// Escapes text for HTML and turns ANSI SGR colors (16, 256 and 24-bit, foreground and
+/// background) and bold into `<span style="...">`. Other escape sequences are dropped.
+fn ansi_to_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut style = AnsiStyle::default();
+    let mut open = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\u{1b}' || chars.peek() != Some(&'[') {
+            out.push_str(&escape_html(c.encode_utf8(&mut [0; 4])));
+            continue;
+        }
+        chars.next();
+
+        // parameter and intermediate bytes run until a final byte in '@'..='~'
+        let mut params = String::new();
+        let mut terminator = None;
+        for c in chars.by_ref() {
+            if ('@'..='~').contains(&c) {
+                terminator = Some(c);
+                break;
+            }
+            params.push(c);
+        }
+        if terminator != Some('m') {
+            continue;
+        }
+        style.apply(&params);
+
+        // Spans never nest: the current one closes before the new style applies
+        if open {
+            out.push_str("</span>");
+        }
+        let css = style.css();
+        open = css.is_some();
+        if let Some(css) = css {
+            out.push_str(&format!("<span style=\"{}\">", css));
+        }
+    }
+    if open {
+        out.push_str("</span>");
+    }
+
+    out
+}
+
+/// Makes text safe to use between the brackets of `![alt](...)`
+#[cfg(feature = "images")]
+fn markdown_alt(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+}
+
+/// Escapes the characters that are significant in HTML text and attribute values.
+/// Everything written into HTML outside a code fence that doesn't come from a trusted
+/// markup representation goes through here, so text like `<script>` stays inert.
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn notebook(cells: Vec<Value>) -> String {
+        json!({ "nbformat": 4, "nbformat_minor": 5, "metadata": {}, "cells": cells }).to_string()
+    }
+
+    fn code_cell(source: Value, outputs: Value) -> Value {
+        json!({ "cell_type": "code", "execution_count": 1, "metadata": {}, "source": source, "outputs": outputs })
+    }
+
+    fn markdown_cell(source: Value) -> Value {
+        json!({ "cell_type": "markdown", "metadata": {}, "source": source })
+    }
+
+    fn convert(cells: Vec<Value>, options: ConvertOptions) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), options)
+            .unwrap()
+            .markdown
+    }
+
+    /// A PNG signature, enough for outputs that are written out without being decoded as images
+    #[cfg(feature = "images")]
+    const PNG: &str = "iVBORw0KGgo=";
+
+    fn display_data(data: Value) -> Value {
+        json!({ "output_type": "display_data", "metadata": {}, "data": data })
+    }
+
+    fn stream(name: &str, text: &str) -> Value {
+        json!({ "output_type": "stream", "name": name, "text": text })
+    }
+
+    fn with_renderer(renderer: Renderer) -> ConvertOptions {
+        ConvertOptions { renderer, ..ConvertOptions::default() }
+    }
+
+    const RENDERERS: [Renderer; 2] = [Renderer::Html, Renderer::Markdown];
+
+    /// Sorted names of the files in `dir`, empty if it doesn't exist
+    #[cfg(feature = "images")]
+    fn asset_files(dir: &Path) -> Vec<String> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn crlf_source_lines_become_lf() {
+        let md = convert(
+            vec![code_cell(json!(["a = 1\r\n", "b = 2\r\n"]), json!([]))],
+            ConvertOptions::default(),
+        );
+        assert!(md.contains("a = 1\nb = 2\n"));
+        assert!(!md.contains('\r'));
+    }
+
+    #[test]
+    fn lone_carriage_returns_are_dropped_from_source() {
+        let md = convert(vec![markdown_cell(json!("a = 1\rb = 2\r"))], ConvertOptions::default());
+        assert!(md.contains("a = 1b = 2"));
+        assert!(!md.contains('\r'));
+    }
+
+    #[test]
+    fn stream_redraw_across_chunks_keeps_final_state() {
+        let outputs = json!([
+            { "output_type": "stream", "name": "stdout", "text": "10%" },
+            { "output_type": "stream", "name": "stdout", "text": "\r20%\n" },
+        ]);
+        let md = convert(vec![code_cell(json!("run()"), outputs)], ConvertOptions::default());
+        assert!(md.contains("20%"));
+        assert!(!md.contains("10%"));
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn identical_images_share_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = vec![
+            code_cell(json!("a"), json!([display_data(json!({ "image/png": PNG }))])),
+            code_cell(json!("b"), json!([display_data(json!({ "image/png": PNG }))])),
+        ];
+        let result =
+            convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), ConvertOptions::default()).unwrap();
+
+        assert_eq!(asset_files(dir.path()).len(), 1);
+        assert_eq!(result.assets.len(), 1);
+        assert_eq!(result.assets_written, 1);
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn hash_collision_gets_a_numbered_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = decode_base64(PNG, "image/png").unwrap();
+        let taken = format!("img_{:08x}.png", fnv1a(&bytes));
+        fs::write(dir.path().join(&taken), b"something else").unwrap();
+
+        let cells = vec![code_cell(json!("a"), json!([display_data(json!({ "image/png": PNG }))]))];
+        let md = convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), ConvertOptions::default())
+            .unwrap()
+            .markdown;
+
+        let suffixed = format!("img_{:08x}_1.png", fnv1a(&bytes));
+        assert!(md.contains(&suffixed));
+        assert_eq!(fs::read(dir.path().join(&suffixed)).unwrap(), bytes);
+        assert_eq!(fs::read(dir.path().join(&taken)).unwrap(), b"something else");
+    }
+
+    #[test]
+    fn generated_asset_names_are_recognized() {
+        assert!(is_generated_asset("img_0123abcd.png"));
+        assert!(is_generated_asset("img_0123abcd_2.svg"));
+        assert!(is_generated_asset("ch1_intro_img_0123abcd.png"));
+        assert!(is_generated_asset("ch1_intro_img_0123abcd_2.png"));
+        assert!(is_generated_asset("ch1_intro_output_003.png"));
+        assert!(!is_generated_asset("ch1_intro_img_0123abcd_x.png"));
+        assert!(!is_generated_asset("logo.png"));
+        assert!(!is_generated_asset("jupyter.css"));
+    }
+
+    #[test]
+    fn numbers_skip_dropped_cells() {
+        let removed = json!({
+            "cell_type": "code", "execution_count": 2, "metadata": { "tags": ["remove-cell"] },
+            "source": "hidden()", "outputs": [],
+        });
+        let cells = vec![
+            code_cell(json!("first()"), json!([])),
+            code_cell(json!(""), json!([])),
+            removed,
+            code_cell(json!("second()"), json!([])),
+        ];
+        let md = convert(cells, ConvertOptions { number_cells: true, ..ConvertOptions::default() });
+
+        assert!(md.contains("<span class=\"cell-num\">[1]</span>\n\n"));
+        assert!(md.contains("<span class=\"cell-num\">[2]</span>\n\n"));
+        assert!(!md.contains("[3]"));
+        assert!(md.find("[2]").unwrap() > md.find("first()").unwrap());
+    }
+
+    #[test]
+    fn title_comes_from_the_first_markdown_cell() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("intro.ipynb");
+        let cells = vec![
+            code_cell(json!("x = 1"), json!([display_data(json!({ "text/plain": "# not a title" }))])),
+            markdown_cell(json!(["Intro text\n", "# The [real] title \n"])),
+            markdown_cell(json!("# Later heading")),
+        ];
+        fs::write(&path, notebook(cells)).unwrap();
+
+        assert_eq!(notebook_title(&path).unwrap().as_deref(), Some("The [real] title"));
+    }
+
+    #[test]
+    fn admonition_fence_outlasts_fences_in_the_cell() {
+        let cell = json!({
+            "cell_type": "markdown", "metadata": { "tags": ["note"] },
+            "source": "````python\nprint(1)\n````",
+        });
+        let md = convert(vec![cell], ConvertOptions { admonition_from_tags: true, ..ConvertOptions::default() });
+
+        assert!(md.contains("`````admonish note\n````python\n"));
+        assert!(md.contains("\n````\n`````\n"));
+    }
+
+    #[cfg(not(feature = "images"))]
+    #[test]
+    fn attachments_are_noted_without_the_images_feature() {
+        let cell = json!({
+            "cell_type": "markdown", "metadata": {},
+            "source": "![plot](attachment:plot.png)",
+            "attachments": { "plot.png": { "image/png": "iVBORw0KGgo=" }, "unused.png": { "image/png": "" } },
+        });
+        let md = convert(vec![cell], ConvertOptions::default());
+
+        assert!(md.contains("<!-- mdbook-jupyter: skipped attachment plot.png, built without the `images` feature -->"));
+        assert!(!md.contains("unused.png"));
+    }
+
+    fn tagged_cell(tags: &[&str]) -> Value {
+        json!({
+            "cell_type": "code", "execution_count": 1, "metadata": { "tags": tags },
+            "source": "print('hi')", "outputs": [stream("stdout", "hello-out\n")],
+        })
+    }
+
+    #[test]
+    fn hide_tags_collapse_only_with_html() {
+        for renderer in RENDERERS {
+            let html = renderer == Renderer::Html;
+
+            let md = convert(vec![tagged_cell(&["hide-input"])], with_renderer(renderer));
+            assert!(md.contains("print('hi')") && md.contains("hello-out"));
+            assert_eq!(md.contains("<details>\n<summary>Show code</summary>"), html);
+
+            let md = convert(vec![tagged_cell(&["hide-output"])], with_renderer(renderer));
+            assert!(md.contains("print('hi')") && md.contains("hello-out"));
+            assert_eq!(md.contains("<details>\n<summary>Show output</summary>"), html);
+        }
+    }
+
+    #[test]
+    fn remove_tags_drop_input_or_output() {
+        for renderer in RENDERERS {
+            let md = convert(vec![tagged_cell(&["remove-input"])], with_renderer(renderer));
+            assert!(!md.contains("print('hi')"));
+            assert!(md.contains("hello-out"));
+
+            let md = convert(vec![tagged_cell(&["remove-output"])], with_renderer(renderer));
+            assert!(md.contains("print('hi')"));
+            assert!(!md.contains("hello-out"));
+            assert!(!md.contains("<details>"));
+        }
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn same_image_in_two_notebooks_shares_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cells = || vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": PNG }))]))];
+        let first = convert_notebook_str(&notebook(cells()), Path::new("a.ipynb"), dir.path(), ConvertOptions::default())
+            .unwrap();
+        let second = convert_notebook_str(&notebook(cells()), Path::new("ch1/b.ipynb"), dir.path(), ConvertOptions::default())
+            .unwrap();
+
+        let files = asset_files(dir.path());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].starts_with("img_"));
+        assert_eq!(first.assets[0].path, second.assets[0].path);
+        assert!(first.assets[0].written);
+        assert!(!second.assets[0].written);
+        assert!(first.markdown.contains(&files[0]) && second.markdown.contains(&files[0]));
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn different_images_in_two_notebooks_get_two_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for (book_path, image) in [("a.ipynb", PNG), ("b.ipynb", "iVBORw0KGgoAAAAN")] {
+            let cells = vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": image }))]))];
+            convert_notebook_str(&notebook(cells), Path::new(book_path), dir.path(), ConvertOptions::default()).unwrap();
+        }
+
+        assert_eq!(asset_files(dir.path()).len(), 2);
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn gif_output_is_written_unchanged() {
+        const GIF: &str = "R0lGODlhAQABAIAAAP///wAAACwAAAAAAQABAAACAkQBADs=";
+        let dir = tempfile::tempdir().unwrap();
+        let cells = vec![code_cell(json!("animate()"), json!([display_data(json!({ "image/gif": GIF }))]))];
+        let result =
+            convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), ConvertOptions::default()).unwrap();
+
+        let files = asset_files(dir.path());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(".gif"));
+        assert!(result.markdown.contains(&files[0]));
+        assert_eq!(fs::read(dir.path().join(&files[0])).unwrap(), STANDARD.decode(GIF).unwrap());
+    }
+    #[test]
+    fn outputs_collapse_only_over_the_line_limit() {
+        let convert_lines = |lines: usize, renderer| {
+            let cells = vec![code_cell(json!("run()"), json!([stream("stdout", &"line\n".repeat(lines))]))];
+            convert(cells, ConvertOptions { collapse_output_over_lines: Some(5), renderer, ..ConvertOptions::default() })
+        };
+
+        assert!(!convert_lines(5, Renderer::Html).contains("<details>"));
+        assert!(convert_lines(6, Renderer::Html).contains("<details>\n<summary>Output (6 lines)</summary>"));
+        assert!(!convert_lines(6, Renderer::Markdown).contains("<details>"));
+    }
+    #[test]
+    fn source_drops_exactly_one_trailing_newline() {
+        let multi = |lines: &[&str]| MultilineString::Multi(lines.iter().map(|s| s.to_string()).collect());
+
+        assert_eq!(multi(&["a = 1\n", "b = 2"]).into_source(), "a = 1\nb = 2");
+        assert_eq!(multi(&["a = 1\n", "b = 2\n"]).into_source(), "a = 1\nb = 2");
+        assert_eq!(multi(&["a = 1\n", "\n"]).into_source(), "a = 1\n");
+        assert_eq!(MultilineString::Single("a = 1\r\n".to_string()).into_source(), "a = 1");
+        assert_eq!(multi(&[]).into_source(), "");
+
+        let md = convert(vec![code_cell(json!(["a = 1\n", "b = 2\n"]), json!([]))], ConvertOptions::default());
+        assert!(md.contains("```python\na = 1\nb = 2\n```"));
+    }
+    #[test]
+    fn blank_cells_are_dropped_unless_kept() {
+        let cells = || vec![code_cell(json!(["\n", "   \n", "\n"]), json!([]))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        assert!(!md.contains("```"));
+        assert!(md.contains("this notebook produced no content"));
+
+        let md = convert(cells(), ConvertOptions { keep_empty_cells: true, ..ConvertOptions::default() });
+        assert!(md.contains("```python\n"));
+        assert!(!md.contains("produced no content"));
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let assets_out = dir.path().join("assets");
+        let cells = vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": PNG }))]))];
+        let options = ConvertOptions { dry_run: true, ..ConvertOptions::default() };
+        let result = convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), &assets_out, options).unwrap();
+
+        assert!(!assets_out.exists());
+        assert_eq!(result.assets.len(), 1);
+        assert!(result.assets[0].written);
+        assert!(result.markdown.contains("img_"));
+    }
+    #[test]
+    fn root_prefix_climbs_one_level_per_directory() {
+        assert_eq!(root_relative_prefix(Path::new("intro.ipynb")), "");
+        assert_eq!(root_relative_prefix(Path::new("ch1/page.ipynb")), "../");
+        assert_eq!(root_relative_prefix(Path::new("ch1/sub/page.ipynb")), "../../");
+        assert_eq!(root_relative_prefix(Path::new("./ch1/page.ipynb")), "../");
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn asset_links_are_relative_to_the_chapter() {
+        let dir = tempfile::tempdir().unwrap();
+        let assets_out = dir.path().join("assets");
+        let link = |book_path: &str| {
+            let cells = vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": PNG }))]))];
+            let md = convert_notebook_str(&notebook(cells), Path::new(book_path), &assets_out, ConvertOptions::default())
+                .unwrap()
+                .markdown;
+            let start = md.find("](").unwrap() + 2;
+            md[start..start + md[start..].find(')').unwrap()].to_string()
+        };
+
+        assert!(link("intro.ipynb").starts_with("assets/img_"));
+        assert!(link("ch1/sub/page.ipynb").starts_with("../../assets/img_"));
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn widget_with_an_image_snapshot_shows_the_image() {
+        let data = json!({
+            "application/vnd.jupyter.widget-view+json": { "model_id": "abc", "version_major": 2, "version_minor": 0 },
+            "image/png": PNG,
+        });
+        let md = convert(vec![code_cell(json!("widget"), json!([display_data(data)]))], ConvertOptions::default());
+
+        assert!(md.contains(".png)"));
+        assert!(!md.contains("widget output"));
+        assert!(!md.contains("model_id"));
+    }
+
+    #[test]
+    fn widget_without_a_snapshot_is_noted() {
+        let data = json!({ "application/vnd.jupyter.widget-view+json": { "model_id": "abc" } });
+        let md = convert(vec![code_cell(json!("widget"), json!([display_data(data)]))], ConvertOptions::default());
+
+        assert!(md.contains("<!-- mdbook-jupyter: skipped a widget output with no static snapshot -->"));
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn repeated_runs_reuse_the_same_asset_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = || {
+            let cells = vec![
+                code_cell(json!("a"), json!([display_data(json!({ "image/png": PNG }))])),
+                code_cell(json!("b"), json!([display_data(json!({ "image/svg+xml": "<svg></svg>" }))])),
+            ];
+            convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), ConvertOptions::default()).unwrap()
+        };
+
+        let first = run();
+        let files = asset_files(dir.path());
+        let second = run();
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(asset_files(dir.path()), files);
+        assert_eq!(second.markdown, first.markdown);
+        assert_eq!(first.assets_written, 2);
+        assert_eq!(second.assets_written, 0);
+    }
+    #[test]
+    fn markdown_outputs_render_or_show_their_source() {
+        let cells = || vec![code_cell(json!("show()"), json!([display_data(json!({ "text/markdown": "**bold** text" }))]))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        assert!(md.contains("\n**bold** text\n\n"));
+        assert!(!md.contains("```markdown"));
+
+        let md = convert(cells(), ConvertOptions { render_markdown_output: false, ..ConvertOptions::default() });
+        assert!(md.contains("```markdown\n**bold** text\n```\n\n"));
+    }
+    #[test]
+    fn executed_cell_without_outputs_keeps_its_prompt() {
+        let cell = json!({ "cell_type": "code", "execution_count": 3, "metadata": {}, "source": "x = 1", "outputs": [] });
+        let md = convert(vec![cell], ConvertOptions { show_prompts: true, ..ConvertOptions::default() });
+
+        assert!(md.contains("<span class=\"jupyter-prompt-in\">In [3]:</span>"));
+        assert!(!md.contains("jupyter-output"));
+        assert!(!md.contains("Out ["));
+    }
+
+    #[test]
+    fn outputs_that_render_empty_leave_no_block() {
+        let md = convert(vec![code_cell(json!("x = 1"), json!([stream("stdout", "\n")]))], ConvertOptions::default());
+
+        assert!(md.contains("x = 1"));
+        assert!(!md.contains("jupyter-output"));
+    }
+    #[test]
+    fn fence_outlasts_backticks_in_the_source() {
+        assert_eq!(fence_for("x = 1"), "```");
+        assert_eq!(fence_for("a `b` c"), "```");
+        assert_eq!(fence_for("```\ncode\n```"), "````");
+        assert_eq!(fence_for("`````"), "``````");
+
+        let md = convert(vec![code_cell(json!("doc = \"\"\"\n```\nexample\n```\n\"\"\""), json!([]))], ConvertOptions::default());
+        assert!(md.contains("````python\ndoc = \"\"\"\n```\nexample\n```\n\"\"\"\n````\n"));
+
+        let md = convert(
+            vec![code_cell(json!("s = '```'"), json!([]))],
+            ConvertOptions { fence_guard: false, ..ConvertOptions::default() },
+        );
+        assert!(md.contains("```python\ns = '```'\n```\n"));
+    }
+    #[test]
+    fn stream_without_a_name_is_stdout() {
+        let outputs = json!([
+            { "output_type": "stream", "text": "out\n" },
+            stream("stdout", "more\n"),
+        ]);
+        let md = convert(vec![code_cell(json!("run()"), outputs)], ConvertOptions::default());
+
+        assert!(md.contains("```\nout\nmore\n"));
+        assert!(!md.contains("stderr"));
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn base64_with_line_breaks_decodes() {
+        assert_eq!(compact_base64("iVBO\nRw0K\r\nGgo=\n"), "iVBORw0KGgo=");
+        assert_eq!(compact_base64(" iVBORw0K\n  Ggo="), "iVBORw0KGgo=");
+        assert_eq!(decode_base64("iVBO\nRw0K\nGgo", "image/png").unwrap(), b"\x89PNG\r\n\x1a\n");
+
+        let wrapped = json!(["iVBO\n", "Rw0K\n", "Ggo=\n"]);
+        let md = convert(vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": wrapped }))]))], ConvertOptions::default());
+        assert!(md.contains(".png)"));
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn invalid_base64_error_names_cell_output_and_mime() {
+        let cells = vec![
+            markdown_cell(json!("# Title")),
+            code_cell(json!("plot()"), json!([stream("stdout", "ok\n"), display_data(json!({ "image/png": "not*base64" }))])),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let error = convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), ConvertOptions::default())
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("cell 1"), "{}", error);
+        assert!(error.contains("output 1"), "{}", error);
+        assert!(error.contains("image/png"), "{}", error);
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn emit_all_mimes_shows_every_representation_in_priority_order() {
+        let cells = || vec![code_cell(json!("show()"), json!([display_data(json!({ "text/markdown": "*caption*", "image/png": PNG }))]))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        assert!(md.contains(".png)"));
+        assert!(!md.contains("*caption*"));
+
+        let md = convert(cells(), ConvertOptions { emit_all_mimes: true, ..ConvertOptions::default() });
+        let image = md.find(".png)").unwrap();
+        let caption = md.find("*caption*").unwrap();
+        assert!(image < caption);
+    }
+    #[test]
+    fn html_special_characters_are_escaped() {
+        assert_eq!(escape_html("<a href='x'>&\"</a>"), "&lt;a href=&#39;x&#39;&gt;&amp;&quot;&lt;/a&gt;");
+
+        let mut md = String::new();
+        push_prompt(&mut md, "in\"><b>", "<In>", None);
+        assert_eq!(md, "<span class=\"in&quot;&gt;&lt;b&gt;\">&lt;In&gt; [ ]:</span>\n\n");
+
+        let outputs = json!([
+            stream("stderr", "<warning> a & b\n"),
+            { "output_type": "error", "ename": "Error<T>", "evalue": "a < b", "traceback": ["in <module>"] },
+        ]);
+        let md = convert(vec![code_cell(json!("run()"), outputs)], ConvertOptions::default());
+        assert!(md.contains("<pre class=\"stderr\">&lt;warning&gt; a &amp; b\n</pre>"));
+        assert!(md.contains("<p><strong>Error&lt;T&gt;</strong>: a &lt; b</p>"));
+        assert!(md.contains("<pre>in &lt;module&gt;</pre>"));
+    }
+    #[test]
+    fn mime_choice_ignores_key_order_and_runs_repeat_exactly() {
+        let with_data = |data: &str| {
+            format!(
+                r#"{{"nbformat": 4, "nbformat_minor": 5, "metadata": {{}}, "cells": [{{"cell_type": "code",
+                "execution_count": 1, "metadata": {{}}, "source": "df", "outputs": [{{"output_type": "execute_result",
+                "execution_count": 1, "metadata": {{}}, "data": {}}}]}}]}}"#,
+                data
+            )
+        };
+        let run = |json: &str| {
+            let dir = tempfile::tempdir().unwrap();
+            convert_notebook_str(json, Path::new("test.ipynb"), dir.path(), ConvertOptions::default())
+                .unwrap()
+                .markdown
+        };
+        let html_first = with_data(r#"{"text/html": "<b>df</b>", "text/plain": "df", "text/latex": "x"}"#);
+        let plain_first = with_data(r#"{"text/plain": "df", "text/latex": "x", "text/html": "<b>df</b>"}"#);
+
+        let md = run(&html_first);
+        assert!(md.contains("<b>df</b>"));
+        assert_eq!(run(&html_first), md);
+        assert_eq!(run(&plain_first), md);
+    }
+    #[test]
+    fn missing_or_null_source_counts_as_empty() {
+        let cells = vec![
+            json!({ "cell_type": "markdown", "metadata": {}, "source": null }),
+            json!({ "cell_type": "code", "execution_count": 1, "metadata": {}, "outputs": [stream("stdout", "kept\n")] }),
+            json!({ "cell_type": "code", "execution_count": 2, "metadata": {}, "source": null, "outputs": [] }),
+            json!({ "cell_type": "raw", "metadata": {} }),
+            markdown_cell(json!("after")),
+        ];
+        let md = convert(cells, ConvertOptions::default());
+
+        assert!(md.contains("kept"));
+        assert!(md.contains("after"));
+        assert!(!md.contains("null"));
+    }
+    #[test]
+    fn non_string_text_values_are_shown_as_json() {
+        assert_eq!(value_to_text(&json!(["a\n", "b"])).as_deref(), Some("a\nb"));
+        assert_eq!(value_to_text(&json!([1, 2.5, 3])).as_deref(), Some("[1,2.5,3]"));
+        assert_eq!(value_to_text(&json!(42)).as_deref(), Some("42"));
+        assert_eq!(value_to_text(&json!(true)).as_deref(), Some("true"));
+        assert_eq!(value_to_text(&Value::Null), None);
+
+        let result = json!({ "output_type": "execute_result", "execution_count": 1, "metadata": {}, "data": { "text/plain": [1, 2, 3] } });
+        let md = convert(vec![code_cell(json!("xs"), json!([result]))], ConvertOptions::default());
+        assert!(md.contains("```\n[1,2,3]\n```"));
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn data_uri_and_bare_base64_give_the_same_image() {
+        let data_uri = format!("data:image/png;base64,{}", PNG);
+        assert_eq!(compact_base64(&data_uri), PNG);
+        assert_eq!(compact_base64(PNG), PNG);
+
+        for value in [PNG.to_string(), data_uri] {
+            let cells = || vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": value }))]))];
+
+            let md = convert(cells(), ConvertOptions { embed_images: true, ..ConvertOptions::default() });
+            assert!(md.contains(&format!("(data:image/png;base64,{})", PNG)));
+
+            let dir = tempfile::tempdir().unwrap();
+            convert_notebook_str(&notebook(cells()), Path::new("test.ipynb"), dir.path(), ConvertOptions::default()).unwrap();
+            let files = asset_files(dir.path());
+            assert_eq!(files.len(), 1);
+            assert_eq!(fs::read(dir.path().join(&files[0])).unwrap(), b"\x89PNG\r\n\x1a\n");
+        }
+    }
+    #[cfg(feature = "webp")]
+    #[test]
+    fn png_converted_to_webp_is_written_as_webp() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(64, 64, image::Rgb([255, 0, 0]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = STANDARD.encode(png.into_inner());
+
+        let dir = tempfile::tempdir().unwrap();
+        let cells = vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": png }))]))];
+        let options = ConvertOptions { convert_images_to: Some("webp".to_string()), ..ConvertOptions::default() };
+        let md = convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), options).unwrap().markdown;
+
+        let files = asset_files(dir.path());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(".webp"));
+        assert!(md.contains(&files[0]));
+        let bytes = fs::read(dir.path().join(&files[0])).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(&bytes[8..12], b"WEBP");
+    }
+    #[test]
+    fn include_tags_keep_only_tagged_cells() {
+        let tagged = |source: &str, tags: Value| json!({ "cell_type": "markdown", "metadata": { "tags": tags }, "source": source });
+        let cells = || vec![tagged("intro", json!(["summary"])), tagged("details", json!(["appendix"])), markdown_cell(json!("plain"))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        assert!(md.contains("intro") && md.contains("details") && md.contains("plain"));
+
+        let md = convert(cells(), ConvertOptions { include_tags: vec!["summary".to_string()], ..ConvertOptions::default() });
+        assert!(md.contains("intro"));
+        assert!(!md.contains("details") && !md.contains("plain"));
+
+        let include_tags = vec!["summary".to_string(), "appendix".to_string()];
+        let md = convert(cells(), ConvertOptions { include_tags, ..ConvertOptions::default() });
+        assert!(md.contains("intro") && md.contains("details"));
+        assert!(!md.contains("plain"));
+    }
+    #[test]
+    fn ansi_colors_become_styled_spans() {
+        assert_eq!(ansi_to_html("\x1b[31mred\x1b[0m plain"), "<span style=\"color:#cd0000\">red</span> plain");
+        assert_eq!(ansi_to_html("\x1b[1;32mok\x1b[0m"), "<span style=\"color:#00cd00;font-weight:bold\">ok</span>");
+        assert_eq!(ansi_to_html("\x1b[38;5;196mx\x1b[0m"), "<span style=\"color:#ff0000\">x</span>");
+        assert_eq!(ansi_to_html("\x1b[38;5;244mx\x1b[0m"), "<span style=\"color:#808080\">x</span>");
+        assert_eq!(
+            ansi_to_html("\x1b[48;2;10;20;300mbg\x1b[m"),
+            "<span style=\"background-color:#0a14ff\">bg</span>"
+        );
+        // Spans don't nest, and a reset leaves the rest unstyled
+        assert_eq!(
+            ansi_to_html("\x1b[31ma\x1b[1mb\x1b[0mc"),
+            "<span style=\"color:#cd0000\">a</span><span style=\"color:#cd0000;font-weight:bold\">b</span>c"
+        );
+        assert_eq!(ansi_to_html("\x1b[2K<b>"), "&lt;b&gt;");
+    }
+    #[test]
+    fn joined_markdown_cells_keep_a_list_together() {
+        let cells = || vec![markdown_cell(json!("Steps:\n\n- one\n")), markdown_cell(json!("- two")), markdown_cell(json!(["- three\n", "\n"]))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        assert!(md.contains("- one\n\n- two\n\n- three\n\n"));
+
+        let md = convert(cells(), ConvertOptions { join_markdown_cells: true, ..ConvertOptions::default() });
+        assert!(md.contains("Steps:\n\n- one\n- two\n- three\n\n"));
+    }
+    #[test]
+    fn interactive_figures_in_different_cells_get_distinct_ids() {
+        let plot = || display_data(json!({ "application/vnd.plotly.v1+json": { "data": [], "layout": {} } }));
+        let cells = vec![code_cell(json!("fig.show()"), json!([plot()])), code_cell(json!("fig.show()"), json!([plot()]))];
+        let md = convert(cells, ConvertOptions { render_interactive: true, ..ConvertOptions::default() });
+
+        let ids: Vec<&str> = md
+            .match_indices("<div id=\"")
+            .map(|(start, _)| {
+                let id = &md[start + "<div id=\"".len()..];
+                &id[..id.find('"').unwrap()]
+            })
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids.iter().all(|id| md.contains(&format!("Plotly.newPlot(\"{}\"", id))));
+    }
+
+    #[test]
+    fn raw_html_output_stays_one_html_block() {
+        let html = "<div>\n<style scoped>\n    .dataframe tbody tr th {\n        vertical-align: top;\n    }\n\n    .dataframe thead th {\n        text-align: right;\n    }\n</style>\n\n<table class=\"dataframe\">\n  <tr><td>1</td></tr>\n</table>\n</div>";
+        let result = json!({ "output_type": "execute_result", "execution_count": 1, "metadata": {}, "data": { "text/html": html } });
+        let cells = || vec![code_cell(json!("df"), json!([result.clone()]))];
+
+        let md = convert(cells(), ConvertOptions::default());
+        let start = md.find("<div>\n<style scoped>").unwrap();
+        let end = md.find("</table>\n</div>").unwrap();
+        assert!(!md[start..end].contains("\n\n"));
+        assert!(md.contains("    }\n    .dataframe thead th {"));
+
+        // As source, the HTML is shown exactly as the notebook stored it
+        let md = convert(cells(), ConvertOptions { render_html_output: false, ..ConvertOptions::default() });
+        assert!(md.contains(&format!("```html\n{}\n```", html)));
+        assert!(!md.contains("<table"));
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_conversion_keeps_a_smaller_original() {
+        // Noise compresses far better lossy than lossless
+        let mut seed = 1u32;
+        let noise = image::RgbImage::from_fn(64, 64, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let [r, g, b, _] = seed.to_be_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        noise.write_to(&mut jpeg, image::ImageFormat::Jpeg).unwrap();
+        let jpeg = jpeg.into_inner();
+
+        assert_eq!(crate::raster::process(&jpeg, "image/jpeg", None, Some("image/webp")).unwrap(), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let cells = vec![code_cell(json!("photo()"), json!([display_data(json!({ "image/jpeg": STANDARD.encode(&jpeg) }))]))];
+        let options = ConvertOptions { convert_images_to: Some("webp".to_string()), ..ConvertOptions::default() };
+        convert_notebook_str(&notebook(cells), Path::new("test.ipynb"), dir.path(), options).unwrap();
+
+        let files = asset_files(dir.path());
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with(".jpg"));
+        assert_eq!(fs::read(dir.path().join(&files[0])).unwrap(), jpeg);
+    }
+    #[cfg(feature = "images")]
+    #[test]
+    fn cleanup_keeps_every_linked_asset() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("ch1_intro_output_000.png");
+        fs::write(&stale, b"old").unwrap();
+
+        // Paths that once shared the asset filename prefix `ch1_intro_`
+        let mut referenced = Vec::new();
+        for (book_path, image) in [("ch1/intro.ipynb", PNG), ("ch1_intro.ipynb", "iVBORw0KGgoAAAAN")] {
+            let cells = vec![code_cell(json!("plot()"), json!([display_data(json!({ "image/png": image }))]))];
+            let result =
+                convert_notebook_str(&notebook(cells), Path::new(book_path), dir.path(), ConvertOptions::default()).unwrap();
+            referenced.extend(result.assets.iter().map(|asset| asset.path.file_name().unwrap().to_string_lossy().into_owned()));
+        }
+
+        let removed = remove_generated_assets(dir.path(), |name| !referenced.iter().any(|r| r == name)).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!stale.exists());
+        referenced.sort();
+        assert_eq!(asset_files(dir.path()), referenced);
+    }
+
+    #[cfg(feature = "images")]
+    #[test]
+    fn alt_text_is_escaped_for_where_it_ends_up() {
+        let cell = || {
+            json!({
+                "cell_type": "code", "execution_count": 1, "metadata": { "mdbook-jupyter": { "alt": "Fit [a \\ b] & <c>" } },
+                "source": "plot()",
+                "outputs": [{
+                    "output_type": "display_data", "data": { "image/png": PNG },
+                    "metadata": { "image/png": { "width": 320 } },
+                }],
+            })
+        };
+
+        let md = convert(vec![cell()], ConvertOptions::default());
+        assert!(md.contains("![Fit \\[a \\\\ b\\] & <c>]("));
+
+        let md = convert(vec![cell()], ConvertOptions { use_img_dimensions: true, ..ConvertOptions::default() });
+        assert!(md.contains("alt=\"Fit [a \\ b] &amp; &lt;c&gt;\" width=\"320\""));
+    }
+
+    #[test]
+    fn nbformat_3_notebooks_are_upgraded() {
+        let notebook = json!({
+            "nbformat": 3, "nbformat_minor": 0, "metadata": {},
+            "worksheets": [
+                { "cells": [
+                    { "cell_type": "heading", "level": 2, "metadata": {}, "source": "Results" },
+                    {
+                        "cell_type": "code", "language": "python", "prompt_number": 3, "metadata": {},
+                        "input": ["x = 6 * 7\n", "print('hello')\n", "x"],
+                        "outputs": [
+                            { "output_type": "stream", "stream": "stdout", "text": ["hello\n"] },
+                            { "output_type": "pyout", "prompt_number": 3, "metadata": {}, "text": ["42"] },
+                        ],
+                    },
+                ]},
+                { "cells": [{
+                    "cell_type": "code", "language": "python", "prompt_number": 4, "metadata": {},
+                    "input": "1 / 0",
+                    "outputs": [{
+                        "output_type": "pyerr", "ename": "ZeroDivisionError", "evalue": "division by zero",
+                        "traceback": ["Traceback (most recent call last)"],
+                    }],
+                }]},
+            ],
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let options = ConvertOptions { show_prompts: true, ..ConvertOptions::default() };
+        let md = convert_notebook_str(&notebook.to_string(), Path::new("v3.ipynb"), dir.path(), options)
+            .unwrap()
+            .markdown;
+
+        let heading = md.find("## Results").unwrap();
+        let input = md.find("In [3]:").unwrap();
+        let source = md.find("```python\nx = 6 * 7\nprint('hello')\nx\n```").unwrap();
+        let stream = md.find("hello\n").unwrap();
+        let result = md.find("Out [3]:").unwrap();
+        let value = md.find("42").unwrap();
+        let error = md.find("<p><strong>ZeroDivisionError</strong>: division by zero</p>").unwrap();
+        assert!(heading < input && input < source && source < stream);
+        assert!(stream < result && result < value && value < error);
+        assert!(md.contains("In [4]:"));
+        assert!(md.contains("```python\n1 / 0\n```"));
+        assert!(md.contains("<pre>Traceback (most recent call last)</pre>"));
+    }
+}
@@ -0,0 +1,58 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors that can occur while converting a notebook
+#[derive(Debug, Error)]
+pub enum ConvertError {
+    /// The notebook file could not be read
+    #[error("failed to read notebook '{path}': {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    /// The notebook is not valid nbformat JSON
+    #[error("failed to parse notebook '{path}' at line {line}, column {column}: {message}")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The notebook's nbformat version isn't one this crate reads (with `strict_nbformat`)
+    #[error("unsupported notebook '{path}': {found}, expected nbformat 3 or 4")]
+    UnsupportedFormat { path: PathBuf, found: String },
+
+    /// Embedded image data is not valid base64
+    #[cfg(feature = "images")]
+    #[error("invalid base64 data for {mime}: {source}")]
+    Base64Decode {
+        mime: String,
+        source: base64::DecodeError,
+    },
+
+    /// A cell could not be converted; `index` counts from 0
+    #[error("cell {index}: {source}")]
+    Cell {
+        index: usize,
+        source: Box<ConvertError>,
+    },
+
+    /// An output of a code cell could not be converted; `index` counts from 0
+    #[error("output {index}: {source}")]
+    Output {
+        index: usize,
+        source: Box<ConvertError>,
+    },
+
+    /// An asset file or the assets directory could not be written
+    #[error("failed to write asset '{path}': {source}")]
+    AssetWrite { path: PathBuf, source: io::Error },
+
+    /// Converted markdown could not be written to the output writer
+    #[error("failed to write markdown: {source}")]
+    Write { source: io::Error },
+}
+
+pub type Result<T, E = ConvertError> = std::result::Result<T, E>;
@@ -1,18 +1,291 @@
 pub mod converter;
 pub mod cli;
+pub mod cache;
+pub mod error;
+#[cfg(any(feature = "resize", feature = "webp"))]
+pub mod raster;
 
-use mdbook::book::{Book, BookItem};
+use log::{error, info, warn};
+use mdbook::book::{Book, BookItem, Chapter};
 use mdbook::errors::Error;
+use mdbook::Config;
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
-use crate::converter::{convert_notebook_to_md_with_options, ConvertOptions};
+use rayon::prelude::*;
+use regex::Regex;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+use crate::cache::{content_hash, options_hash, ConversionCache};
+use crate::converter::{
+    convert_notebook_to_md_with_options, error_placeholder, remove_generated_assets, AssetInfo, ConvertOptions, ConvertResult, Renderer,
+};
+
+/// Environment variable that makes the preprocessor only check notebooks, see `JupyterPreprocessor::check`
+pub const CHECK_ENV: &str = "MDBOOK_JUPYTER_CHECK";
+
+/// Assets directory relative to the build dir unless `assets_dir` is configured
+const DEFAULT_ASSETS_DIR: &str = "html/assets";
+
+/// Stylesheet for the classes the converter emits, used by `inject_css`
+const JUPYTER_CSS: &str = include_str!("jupyter.css");
+
+/// Keys of the preprocessor table that mdbook itself interprets
+const MDBOOK_KEYS: [&str; 6] = ["command", "renderer", "renderers", "before", "after", "optional"];
+
+/// Renderers the preprocessor supports unless book.toml lists others
+const DEFAULT_RENDERERS: [&str; 2] = ["html", "markdown"];
 
 /// Jupyter preprocessor for mdbook
-pub struct JupyterPreprocessor;
+pub struct JupyterPreprocessor {
+    renderers: Vec<String>,
+}
 
 impl JupyterPreprocessor {
     pub fn new() -> Self {
-        JupyterPreprocessor
+        JupyterPreprocessor {
+            renderers: DEFAULT_RENDERERS.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    /// Creates a preprocessor supporting the given renderers
+    pub fn with_renderers(renderers: Vec<String>) -> Self {
+        JupyterPreprocessor { renderers }
+    }
+
+    /// Creates a preprocessor honoring the optional `renderers` array of `[preprocessor.jupyter]`
+    pub fn from_config(config: &Config) -> Self {
+        let renderers = config
+            .get_preprocessor("jupyter")
+            .and_then(|cfg| cfg.get("renderers"))
+            .and_then(|v| v.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect::<Vec<_>>()
+            });
+
+        match renderers {
+            Some(renderers) => Self::with_renderers(renderers),
+            None => Self::new(),
+        }
+    }
+
+    /// Build conversion options from the `[preprocessor.jupyter]` table, keeping defaults for missing keys.
+    /// Unknown keys and values of the wrong type are reported and ignored.
+    fn convert_options(&self, config: &Config, renderer: &str) -> ConvertOptions {
+        let defaults = ConvertOptions::default();
+        let table = config
+            .get_preprocessor(self.name())
+            .and_then(|cfg| serde_json::to_value(cfg).ok());
+
+        let mut options = match (serde_json::to_value(&defaults), table) {
+            (Ok(JsonValue::Object(mut merged)), Some(JsonValue::Object(table))) => {
+                for (key, value) in table {
+                    if MDBOOK_KEYS.contains(&key.as_str()) {
+                        continue;
+                    }
+                    let Some(current) = merged.get(&key) else {
+                        warn!("jupyter: ignoring unknown option '{}'", key);
+                        continue;
+                    };
+                    let value = match (key.as_str(), current, value) {
+                        // Configured aliases extend the built-in ones instead of replacing them
+                        ("language_aliases", JsonValue::Object(builtin), JsonValue::Object(aliases)) => {
+                            let mut builtin = builtin.clone();
+                            builtin.extend(aliases);
+                            JsonValue::Object(builtin)
+                        }
+                        (_, _, value) => value,
+                    };
+
+                    // Check each value on its own so one mistake doesn't discard the whole table
+                    let mut candidate = merged.clone();
+                    candidate.insert(key.clone(), value);
+                    match serde_json::from_value::<ConvertOptions>(JsonValue::Object(candidate.clone())) {
+                        Ok(_) => merged = candidate,
+                        Err(e) => warn!("jupyter: ignoring invalid option '{}': {}", key, e),
+                    }
+                }
+                serde_json::from_value(JsonValue::Object(merged)).unwrap_or(defaults)
+            }
+            _ => defaults,
+        };
+        options.renderer = Renderer::from_name(renderer);
+
+        if options.max_image_width.is_some() && cfg!(not(feature = "resize")) {
+            warn!("jupyter: max_image_width needs the `resize` feature; images are left at full size");
+        }
+        if let Some(format) = &options.convert_images_to {
+            if format != "webp" {
+                warn!("jupyter: unsupported convert_images_to '{}', only \"webp\" is known", format);
+            } else if cfg!(not(feature = "webp")) {
+                warn!("jupyter: convert_images_to needs the `webp` feature; images keep their format");
+            }
+        }
+
+        options
     }
+
+    /// Converts every notebook of the book, including those pulled into markdown chapters with
+    /// `{{#include}}`, for the HTML renderer without writing assets or changing the book, and fails
+    /// if any notebook doesn't convert. Returns the number of notebooks checked.
+    pub fn check(&self, root: &Path, config: &Config, book: &Book) -> Result<usize, Error> {
+        let mut options = self.convert_options(config, "html");
+        options.dry_run = true;
+
+        let assets_dir = assets_dir(root, config, &options)?;
+        let src_dir = root.join(&config.book.src);
+
+        // Notebook chapters, then notebooks included into markdown chapters, each with the path
+        // it is converted under
+        let mut notebooks: Vec<(PathBuf, PathBuf)> =
+            notebook_chapters(book).into_iter().map(|path| (path.clone(), path)).collect();
+        for item in book.iter() {
+            if let BookItem::Chapter(Chapter { path: Some(path), content, .. }) = item {
+                if !is_notebook(path) {
+                    let includes = notebook_includes(content, path);
+                    notebooks.extend(includes.into_iter().map(|include| (include.notebook, include.book_path)));
+                }
+            }
+        }
+
+        let mut failed = 0;
+        for (path, book_path) in &notebooks {
+            if let Err(e) = convert_notebook_to_md_with_options(&src_dir.join(path), book_path, &assets_dir, options.clone()) {
+                error!("Error converting notebook '{}': {}", path.display(), e);
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            return Err(Error::msg(format!(
+                "jupyter: {} of {} notebooks failed to convert",
+                failed,
+                notebooks.len()
+            )));
+        }
+        info!("jupyter: all {} notebooks converted cleanly", notebooks.len());
+        Ok(notebooks.len())
+    }
+}
+
+/// File names of the asset files a conversion links to
+fn asset_names(assets: &[AssetInfo]) -> Vec<String> {
+    assets
+        .iter()
+        .filter_map(|asset| asset.path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn is_notebook(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "ipynb")
+}
+
+/// Book-relative paths of the chapters that are notebooks
+fn notebook_chapters(book: &Book) -> Vec<PathBuf> {
+    book.iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => chapter.path.clone(),
+            _ => None,
+        })
+        .filter(|path| is_notebook(path))
+        .collect()
+}
+
+/// `{{#include path.ipynb}}` directives, capturing the notebook path
+static NOTEBOOK_INCLUDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{\{#include\s+([^}\s]+\.ipynb)\s*\}\}").expect("include pattern is valid"));
+
+/// A `{{#include path.ipynb}}` directive in a markdown chapter
+struct NotebookInclude {
+    /// Byte range of the directive in the chapter content
+    range: Range<usize>,
+    /// The included notebook, relative to the book source
+    notebook: PathBuf,
+    /// Path the notebook is converted under. Asset links are relative to the page showing them,
+    /// so it is converted as if it sat next to the chapter.
+    book_path: PathBuf,
+}
+
+/// The notebook includes of a markdown chapter, in order. Escaped directives (`\{{#include ...}}`)
+/// are left out.
+fn notebook_includes(content: &str, chapter_path: &Path) -> Vec<NotebookInclude> {
+    let chapter_dir = chapter_path.parent().unwrap_or(Path::new(""));
+
+    NOTEBOOK_INCLUDE
+        .captures_iter(content)
+        .filter_map(|caps| {
+            let (directive, target) = (caps.get(0)?, caps.get(1)?);
+            if content[..directive.start()].ends_with('\\') {
+                return None;
+            }
+            let notebook = chapter_dir.join(target.as_str());
+            let book_path = chapter_dir.join(notebook.file_name().unwrap_or_default());
+            Some(NotebookInclude {
+                range: directive.range(),
+                notebook,
+                book_path,
+            })
+        })
+        .collect()
+}
+
+/// Replaces `{{#include path.ipynb}}` directives in a markdown chapter with the converted notebook,
+/// adding the names of the asset files it links to to `referenced`.
+/// mdbook's `links` preprocessor expands includes itself, so this only sees them when the jupyter
+/// preprocessor is ordered before it.
+fn expand_notebook_includes(
+    content: &str,
+    chapter_path: &Path,
+    src_dir: &Path,
+    assets_dir: &Path,
+    options: &ConvertOptions,
+    referenced: &mut HashSet<String>,
+) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut last = 0;
+    for include in notebook_includes(content, chapter_path) {
+        out.push_str(&content[last..include.range.start]);
+        last = include.range.end;
+
+        let notebook = &include.notebook;
+        match convert_notebook_to_md_with_options(&src_dir.join(notebook), &include.book_path, assets_dir, options.clone()) {
+            Ok(result) => {
+                referenced.extend(asset_names(&result.assets));
+                out.push_str(&result.markdown);
+            }
+            Err(e) => {
+                error!("Error converting notebook '{}' included in '{}': {}", notebook.display(), chapter_path.display(), e);
+                out.push_str(&error_placeholder(notebook, &e.to_string(), options));
+            }
+        }
+    }
+    out.push_str(&content[last..]);
+
+    out
+}
+
+/// Where assets are written: the configured `assets_dir` (or the default) under the build dir
+fn assets_dir(root: &Path, config: &Config, options: &ConvertOptions) -> Result<PathBuf, Error> {
+    let assets_rel = Path::new(options.assets_dir.as_deref().unwrap_or(DEFAULT_ASSETS_DIR));
+    if !is_inside_build_dir(assets_rel) {
+        return Err(Error::msg(format!(
+            "jupyter: assets_dir '{}' must be a relative path inside the build directory",
+            assets_rel.display()
+        )));
+    }
+    Ok(root.join(&config.build.build_dir).join(assets_rel))
+}
+
+/// True for a non-empty relative path without `..`, i.e. one that can't leave the build dir
+fn is_inside_build_dir(path: &Path) -> bool {
+    path.components().any(|c| matches!(c, Component::Normal(_)))
+        && path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
 }
 
 impl Default for JupyterPreprocessor {
@@ -27,53 +300,175 @@ impl Preprocessor for JupyterPreprocessor {
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        eprintln!("Running Jupyter preprocessor");
-        let assets_dir = ctx
+        info!("Running Jupyter preprocessor");
+
+        // CI mode: fail the build on broken notebooks instead of rendering error placeholders
+        if env::var_os(CHECK_ENV).is_some() {
+            self.check(&ctx.root, &ctx.config, &book)?;
+            return Ok(book);
+        }
+
+        // Extract configuration from the preprocessor config
+        let options = self.convert_options(&ctx.config, &ctx.renderer);
+        let assets_dir = assets_dir(&ctx.root, &ctx.config, &options)?;
+
+        // Converted markdown is cached by notebook content; changing options invalidates everything.
+        // Each renderer gets its own file so alternating renderer passes don't wipe each other's cache.
+        let options_hash = options_hash(&options);
+        let cache_path = ctx
             .root
             .join(&ctx.config.build.build_dir)
-            .join("html/assets");
+            .join(format!(".jupyter-cache-{}.json", ctx.renderer));
+        let mut cache = ConversionCache::load(&cache_path, options_hash);
 
-        // Extract configuration from the preprocessor config
-        let options = ctx.config.get_preprocessor(self.name())
-            .and_then(|cfg| {
-                // Try to extract embed_images boolean from config table
-                cfg.get("embed_images")
-                    .and_then(|v| v.as_bool())
-                    .map(|embed_images| ConvertOptions { embed_images })
-            })
-            .unwrap_or_default();
+        // Collect notebook chapters up front so they can be converted in parallel
+        let notebooks = notebook_chapters(&book);
+
+        // Only plain data is captured here; the context itself isn't shareable across threads
+        let src_dir = ctx.root.join(&ctx.config.book.src);
+        let convert = |path: &PathBuf| -> (PathBuf, Option<u64>, bool, Result<ConvertResult, String>) {
+            let full_path = src_dir.join(path);
+            let hash = fs::read(&full_path)
+                .ok()
+                .map(|bytes| content_hash(&bytes, options_hash));
+
+            // Cached markdown links to asset files, so only trust it while they all still exist
+            if let Some(entry) = hash
+                .and_then(|hash| cache.get(&path.display().to_string(), hash))
+                .filter(|entry| entry.assets.iter().all(|name| assets_dir.join(name).exists()))
+            {
+                let result = ConvertResult {
+                    markdown: entry.markdown.clone(),
+                    assets_written: 0,
+                    assets: Vec::new(),
+                };
+                return (path.clone(), hash, true, Ok(result));
+            }
+
+            let result = convert_notebook_to_md_with_options(&full_path, path, &assets_dir, options.clone())
+                .map_err(|e| e.to_string());
+            (path.clone(), hash, false, result)
+        };
+
+        let results: Vec<_> = if options.parallel {
+            notebooks.par_iter().map(&convert).collect()
+        } else {
+            notebooks.iter().map(&convert).collect()
+        };
+
+        let (mut converted_count, mut cached_count, mut assets_count, mut error_count) = (0, 0, 0, 0);
+        let mut converted: HashMap<PathBuf, Result<String, String>> = HashMap::new();
+        // Asset files some chapter links to; every other generated file is stale
+        let mut referenced: HashSet<String> = HashSet::new();
+        for (path, hash, cached, result) in results {
+            match &result {
+                Ok(_) if cached => {
+                    cached_count += 1;
+                    if let Some(entry) = hash.and_then(|hash| cache.get(&path.display().to_string(), hash)) {
+                        referenced.extend(entry.assets.iter().cloned());
+                    }
+                }
+                Ok(result) => {
+                    converted_count += 1;
+                    assets_count += result.assets_written;
+                    let assets = asset_names(&result.assets);
+                    referenced.extend(assets.iter().cloned());
+                    if let Some(hash) = hash {
+                        cache.insert(path.display().to_string(), hash, result.markdown.clone(), assets);
+                    }
+                }
+                Err(_) => error_count += 1,
+            }
+            converted.insert(path, result.map(|r| r.markdown));
+        }
+
+        info!(
+            "jupyter: converted {} notebooks ({} unchanged, from cache), wrote {} image assets, {} errors",
+            converted_count, cached_count, assets_count, error_count
+        );
+
+        // Preprocessors can't add to the renderer's additional-css, so the styles go inline into
+        // the first notebook chapter and a copy is left in the assets dir
+        let mut style = None;
+        if options.inject_css && options.renderer == Renderer::Html {
+            style = Some(format!("<style>\n{}</style>\n\n", JUPYTER_CSS));
+            if !options.dry_run {
+                let css_path = assets_dir.join("jupyter.css");
+                if let Err(e) = fs::create_dir_all(&assets_dir).and_then(|_| fs::write(&css_path, JUPYTER_CSS)) {
+                    warn!("Could not write '{}': {}", css_path.display(), e);
+                }
+            }
+        }
 
         book.for_each_mut(|item| {
             if let BookItem::Chapter(chapter) = item {
                 if let Some(path) = &chapter.path {
-                    if path.extension().map_or(false, |ext| ext == "ipynb") {
-                        let full_path = ctx.root.join(&ctx.config.book.src).join(path);
-                        match convert_notebook_to_md_with_options(&full_path, &assets_dir, options.clone()) {
-                            Ok(content) => chapter.content = content,
-                            Err(e) => {
-                                // Log the error to stderr so the mdbook user sees the underlying cause
-                                eprintln!("Error converting notebook '{}': {}", path.display(), e);
-
-                                // Inject a visible error message into the generated chapter content
-                                // so the book shows an informative placeholder rather than an empty page.
-                                chapter.content = format!(
-                                    "<!-- mdbook-jupyter: conversion error -->\n\n> **Notebook conversion failed** for `{}`\n\n```
-{}\n```
-\n\nPlease check the original notebook and converter logs for details.",
-                                    path.display(),
-                                    e
-                                );
-                            }
+                    match converted.get(path) {
+                        Some(Ok(content)) => {
+                            chapter.content = match style.take() {
+                                Some(style) => style + content,
+                                None => content.clone(),
+                            };
+                        }
+                        Some(Err(e)) => {
+                            // Log the error so the mdbook user sees the underlying cause
+                            error!("Error converting notebook '{}': {}", path.display(), e);
+
+                            // Inject a visible error message into the generated chapter content
+                            // so the book shows an informative placeholder rather than an empty page.
+                            chapter.content = error_placeholder(path, e, &options);
+                        }
+                        None if chapter.content.contains("{{#include") => {
+                            chapter.content = expand_notebook_includes(
+                                &chapter.content,
+                                path,
+                                &src_dir,
+                                &assets_dir,
+                                &options,
+                                &mut referenced,
+                            );
                         }
+                        None => {}
                     }
                 }
             }
         });
 
+        // Only once every notebook, included ones too, is converted is it known which files are
+        // still linked: images of removed notebooks, or of outputs a notebook no longer has, go
+        if options.clean_assets && !options.embed_images && !options.dry_run {
+            if let Err(e) = remove_generated_assets(&assets_dir, |name| !referenced.contains(name)) {
+                warn!("Could not clean assets in '{}': {}", assets_dir.display(), e);
+            }
+        }
+
+        // A dry run leaves the build directory untouched, cache included
+        if !options.dry_run {
+            if let Err(e) = cache.save() {
+                warn!("Could not write notebook cache '{}': {}", cache_path.display(), e);
+            }
+        }
+
         Ok(book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html" || renderer == "markdown"
+        self.renderers.iter().any(|r| r == renderer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_resolve_next_to_the_chapter() {
+        let content = "Intro\n{{#include ../data/run.ipynb}}\n\\{{#include skipped.ipynb}}\n{{#include other.md}}\n";
+        let includes = notebook_includes(content, Path::new("ch1/page.md"));
+
+        assert_eq!(includes.len(), 1);
+        assert_eq!(&content[includes[0].range.clone()], "{{#include ../data/run.ipynb}}");
+        assert_eq!(includes[0].notebook, Path::new("ch1/../data/run.ipynb"));
+        assert_eq!(includes[0].book_path, Path::new("ch1/run.ipynb"));
     }
 }
@@ -1,8 +1,10 @@
 use clap::{Parser, Subcommand};
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
+use mdbook::Config;
 use mdbook_jupyter::cli;
 use mdbook_jupyter::JupyterPreprocessor;
 use std::io;
+use std::path::PathBuf;
 use std::process;
 
 #[derive(Parser)]
@@ -19,27 +21,112 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     /// Install the preprocessor into book.toml
-    Install,
+    Install {
+        /// Also set `embed_images = true`
+        #[clap(long)]
+        embed_images: bool,
+        /// Register for the supported renderers: `renderers = ["html", "markdown"]`
+        #[clap(long)]
+        renderers: bool,
+        /// Run before the named preprocessor (repeatable)
+        #[clap(long, value_name = "NAME")]
+        before: Vec<String>,
+        /// Run after the named preprocessor (repeatable)
+        #[clap(long, value_name = "NAME")]
+        after: Vec<String>,
+    },
     /// Check if the preprocessor supports a given renderer
     Supports { renderer: String },
+    /// Convert a single notebook to markdown without building a book
+    Convert {
+        /// Notebook to convert
+        input: PathBuf,
+        /// Write the markdown to this file instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Embed images as base64 instead of writing asset files
+        #[clap(long)]
+        embed_images: bool,
+        /// Report the files that would be written without writing anything
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Check that every notebook in a book converts, without writing anything
+    Check {
+        /// Book root containing book.toml
+        #[clap(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Print a SUMMARY.md fragment listing the notebooks in a directory
+    GenSummary {
+        /// Book source directory searched recursively for notebooks
+        src: PathBuf,
+    },
+    /// Convert every notebook in a directory, mirroring its structure as markdown files
+    ConvertDir {
+        /// Directory searched recursively for notebooks
+        src: PathBuf,
+        /// Directory the markdown files and their `assets` folder are written to
+        dest: PathBuf,
+        /// Embed images as base64 instead of writing asset files
+        #[clap(long)]
+        embed_images: bool,
+    },
 }
 
 fn main() {
+    // Log to stderr (stdout carries the book JSON); RUST_LOG overrides the default level
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let cli = Cli::parse();
 
     if let Some(command) = cli.command {
         match command {
-            Command::Install => {
-                if let Err(e) = cli::handle_install() {
+            Command::Install { embed_images, renderers, before, after } => {
+                let install = cli::InstallOptions {
+                    embed_images,
+                    renderers,
+                    before,
+                    after,
+                };
+                if let Err(e) = cli::handle_install(&install) {
                     eprintln!("Error: {}", e);
                     process::exit(1);
                 }
             }
             Command::Supports { renderer } => {
-                let preprocessor = JupyterPreprocessor::new();
+                // mdbook runs this from the book root, so pick up any configured renderers
+                let preprocessor = match Config::from_disk("book.toml") {
+                    Ok(config) => JupyterPreprocessor::from_config(&config),
+                    Err(_) => JupyterPreprocessor::new(),
+                };
                 let supported = cli::handle_supports(&preprocessor, &renderer);
                 process::exit(if supported { 0 } else { 1 });
             }
+            Command::Convert { input, output, embed_images, dry_run } => {
+                if let Err(e) = cli::handle_convert(&input, output.as_deref(), embed_images, dry_run) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+            Command::Check { dir } => {
+                if let Err(e) = cli::handle_check(&dir) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+            Command::GenSummary { src } => {
+                if let Err(e) = cli::handle_gen_summary(&src) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
+            Command::ConvertDir { src, dest, embed_images } => {
+                if let Err(e) = cli::handle_convert_dir(&src, &dest, embed_images) {
+                    eprintln!("Error: {}", e);
+                    process::exit(1);
+                }
+            }
         }
     } else if let Err(e) = handle_preprocessing() {
         eprintln!("Error: {}", e);
@@ -51,7 +138,7 @@ fn handle_preprocessing() -> Result<(), Box<dyn std::error::Error>> {
     let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
     cli::check_version_compatibility(&ctx.mdbook_version)?;
 
-    let preprocessor = JupyterPreprocessor::new();
+    let preprocessor = JupyterPreprocessor::from_config(&ctx.config);
     let processed_book = preprocessor.run(&ctx, book)?;
     serde_json::to_writer(io::stdout(), &processed_book)?;
 
@@ -0,0 +1,41 @@
+//! Raster image processing for notebook outputs, compiled in with the `resize` or `webp` feature
+
+use image::imageops::FilterType;
+use image::{ImageFormat, ImageResult};
+use std::io::Cursor;
+
+/// Downscales an image wider than `max_width` to that width, keeping its aspect ratio, and
+/// re-encodes it as `target_mime` if given. Returns `None` when nothing had to change, the
+/// format isn't supported, or a re-encoding alone would make the file larger.
+pub fn process(
+    bytes: &[u8],
+    mime: &str,
+    max_width: Option<u32>,
+    target_mime: Option<&str>,
+) -> ImageResult<Option<Vec<u8>>> {
+    let Some(format) = ImageFormat::from_mime_type(mime) else {
+        return Ok(None);
+    };
+    let target = target_mime
+        .and_then(ImageFormat::from_mime_type)
+        .filter(|target| *target != format);
+
+    let mut img = image::load_from_memory_with_format(bytes, format)?;
+    let max_width = max_width.filter(|max| img.width() > *max);
+    if max_width.is_none() && target.is_none() {
+        return Ok(None);
+    }
+    if let Some(max_width) = max_width {
+        // `resize` fits within the bounds while preserving the aspect ratio, so the width decides
+        img = img.resize(max_width, img.height(), FilterType::Lanczos3);
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, target.unwrap_or(format))?;
+    let out = out.into_inner();
+    // The WebP encoder is lossless only, which often grows photos
+    if max_width.is_none() && out.len() >= bytes.len() {
+        return Ok(None);
+    }
+    Ok(Some(out))
+}
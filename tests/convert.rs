@@ -0,0 +1,49 @@
+use mdbook_jupyter::converter::{convert_notebook_str, ConvertOptions, Renderer};
+use serde_json::json;
+use std::path::Path;
+
+fn convert(options: ConvertOptions) -> String {
+    let notebook = json!({
+        "nbformat": 4,
+        "nbformat_minor": 5,
+        "metadata": {},
+        "cells": [{
+            "cell_type": "code",
+            "execution_count": 1,
+            "metadata": {},
+            "source": "print('hi')",
+            "outputs": [{ "output_type": "stream", "name": "stdout", "text": "hi\n" }],
+        }],
+    });
+    let dir = tempfile::tempdir().unwrap();
+    convert_notebook_str(&notebook.to_string(), Path::new("intro.ipynb"), dir.path(), options)
+        .unwrap()
+        .markdown
+}
+
+#[test]
+fn html_wraps_input_and_output() {
+    let md = convert(ConvertOptions::default());
+
+    let input = md.find("<div class=\"jupyter-input\">\n\n```python\nprint('hi')\n```\n\n</div>").unwrap();
+    let output = md.find("<div class=\"jupyter-output\">\n\n").unwrap();
+    assert!(input < output);
+    assert!(md[output..].contains("hi\n"));
+}
+
+#[test]
+fn markdown_renderer_has_no_wrappers() {
+    let md = convert(ConvertOptions { renderer: Renderer::Markdown, ..ConvertOptions::default() });
+
+    assert!(md.contains("```python\nprint('hi')\n```"));
+    assert!(!md.contains("jupyter-input"));
+    assert!(!md.contains("jupyter-output"));
+}
+
+#[test]
+fn wrappers_can_be_turned_off() {
+    let md = convert(ConvertOptions { wrap_input: false, wrap_output: false, ..ConvertOptions::default() });
+
+    assert!(!md.contains("jupyter-input"));
+    assert!(!md.contains("jupyter-output"));
+}